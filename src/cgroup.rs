@@ -0,0 +1,117 @@
+use std::ffi::{CStr, CString};
+use std::ops::Not;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// A cgroup v2 group holding the backend process, removed again on drop
+pub struct Cgroup {
+    path: PathBuf,
+    procs: CString,
+}
+
+impl Cgroup {
+    // Create the cgroup with the given limits, None when cgroup v2 is unavailable or not permitted
+    pub fn create(name: &str, memory_max: Option<&str>, cpu_max: Option<&str>) -> Option<Self> {
+        match Self::try_create(name, memory_max, cpu_max) {
+            Ok(cgroup) => {
+                log::info!("[Cgroup] Created cgroup: {}", cgroup.path.display());
+                Some(cgroup)
+            }
+            Err(e) => {
+                log::warn!("[Cgroup] Skip resource limits: {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn try_create(
+        name: &str,
+        memory_max: Option<&str>,
+        cpu_max: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let root = Path::new(CGROUP_ROOT);
+        if root.join("cgroup.controllers").exists().not() {
+            anyhow::bail!("cgroup v2 is not mounted at {}", CGROUP_ROOT)
+        }
+
+        // Limits only apply once the controllers are enabled for the children of the root
+        let subtree_control = root.join("cgroup.subtree_control");
+        let enabled = std::fs::read_to_string(&subtree_control)
+            .context(format!("Failed to read {}", subtree_control.display()))?;
+        for (controller, limit) in [("memory", memory_max), ("cpu", cpu_max)] {
+            if limit.is_some() && enabled.split_whitespace().all(|c| c != controller) {
+                std::fs::write(&subtree_control, format!("+{}", controller))
+                    .context(format!("Failed to enable the {} controller", controller))?;
+            }
+        }
+
+        let path = root.join(name);
+        if path.exists().not() {
+            std::fs::create_dir(&path)
+                .context(format!("Failed to create cgroup: {}", path.display()))?;
+        }
+        let cgroup = Self {
+            procs: CString::new(path.join("cgroup.procs").as_os_str().as_bytes())?,
+            path,
+        };
+        if let Some(memory_max) = memory_max {
+            cgroup.write("memory.max", memory_max)?;
+            log::info!("[Cgroup] memory.max: {}", memory_max);
+        }
+        if let Some(cpu_max) = cpu_max {
+            cgroup.write("cpu.max", cpu_max)?;
+            log::info!("[Cgroup] cpu.max: {}", cpu_max);
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> anyhow::Result<()> {
+        let path = self.path.join(file);
+        std::fs::write(&path, value).context(format!(
+            "Failed to write {} to {}",
+            value,
+            path.display()
+        ))
+    }
+
+    // Path of cgroup.procs, for use with attach in a forked child
+    pub fn procs(&self) -> CString {
+        self.procs.clone()
+    }
+
+    // Move the calling process into the cgroup, only async-signal-safe calls so it can run before exec
+    pub fn attach(procs: &CStr) -> std::io::Result<()> {
+        unsafe {
+            let fd = nix::libc::open(procs.as_ptr(), nix::libc::O_WRONLY);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let written = nix::libc::write(fd, b"0".as_ptr().cast(), 1);
+            let err = std::io::Error::last_os_error();
+            nix::libc::close(fd);
+            if written != 1 {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, pid: u32) -> bool {
+        std::fs::read_to_string(self.path.join("cgroup.procs"))
+            .map(|procs| procs.lines().any(|p| p.trim() == pid.to_string()))
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // The kernel only removes an empty cgroup, leftovers are reused on the next start
+        if let Err(e) = std::fs::remove_dir(&self.path) {
+            log::debug!("[Cgroup] Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
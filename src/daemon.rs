@@ -6,6 +6,7 @@ use anyhow::Context;
 use rand::Rng;
 
 use crate::env;
+use crate::error::ErrorKind;
 
 use crate::util;
 use crate::xunlei_asset;
@@ -18,7 +19,8 @@ pub struct XunleiInstall {
     description: &'static str,
     auth_user: Option<String>,
     auth_password: Option<String>,
-    host: std::net::IpAddr,
+    auth_file: Option<PathBuf>,
+    host: crate::ListenHost,
     port: u16,
     debug: bool,
     download_path: PathBuf,
@@ -41,6 +43,7 @@ impl From<(bool, Config)> for XunleiInstall {
             gid: value.1.gid.unwrap_or(nix::unistd::getgid().into()),
             auth_user: value.1.auth_user,
             auth_password: value.1.auth_password,
+            auth_file: value.1.auth_file,
             debug: value.0,
         }
     }
@@ -53,27 +56,32 @@ impl XunleiInstall {
 
         if self.config_path.is_dir().not() {
             std::fs::create_dir_all(&self.config_path)?;
-            util::recursive_chown(&&self.config_path, self.uid, self.gid);
+            util::recursive_chown(&self.config_path, self.uid, self.gid);
         } else if self.config_path.is_file() {
-            return Err(anyhow::anyhow!("Config path must be a directory"));
+            return Err(
+                anyhow::anyhow!("Config path must be a directory").context(ErrorKind::Config)
+            );
         }
 
         // the real store download path
         if self.download_path.is_dir().not() {
             util::create_dir_all(&self.download_path, 0o755)?;
-            util::recursive_chown(&&self.download_path, self.uid, self.gid);
+            util::recursive_chown(&self.download_path, self.uid, self.gid);
         } else if self.download_path.is_file() {
-            return Err(anyhow::anyhow!("download path must be a directory"));
+            return Err(
+                anyhow::anyhow!("download path must be a directory").context(ErrorKind::Config)
+            );
         }
 
         // mount bind downloads directory
         if self.mount_bind_download_path.is_dir().not() {
             util::create_dir_all(&self.mount_bind_download_path, 0o755)?;
-            util::recursive_chown(&&self.mount_bind_download_path, self.uid, self.gid);
+            util::recursive_chown(&self.mount_bind_download_path, self.uid, self.gid);
         } else if self.mount_bind_download_path.is_file() {
-            return Err(anyhow::anyhow!(
-                "mount bind download path must be a directory"
-            ));
+            return Err(
+                anyhow::anyhow!("mount bind download path must be a directory")
+                    .context(ErrorKind::Config),
+            );
         }
 
         log::info!(
@@ -99,11 +107,14 @@ impl XunleiInstall {
 
         util::create_dir_all(&target_dir, 0o755)?;
 
-        let xunlei = xunlei_asset::asset()?;
-        for file in xunlei.iter()? {
+        let xunlei = xunlei_asset::asset().context(ErrorKind::Asset)?;
+        for file in xunlei.iter().context(ErrorKind::Asset)? {
             let filename = file.as_str();
             let target_filepath = target_dir.join(filename);
-            let data = xunlei.get(filename).context("Read data failure")?;
+            let data = xunlei
+                .get(filename)
+                .context("Read data failure")
+                .context(ErrorKind::Asset)?;
             util::write_file(&target_filepath, data, 0o755)?;
             log::info!("[XunleiInstall] Install to: {}", target_filepath.display());
             util::chown(&target_filepath, self.uid, self.gid).context(format!(
@@ -123,7 +134,7 @@ impl XunleiInstall {
             ))?,
             0o755,
         )?;
-        let mut byte_arr = vec![0u8; 32];
+        let mut byte_arr = [0u8; 32];
         rand::thread_rng().fill(&mut byte_arr[..]);
         let hex_string = byte_arr
             .iter()
@@ -179,7 +190,7 @@ impl XunleiInstall {
                 target_syno_authenticate_path.display()
             ))?;
 
-        util::recursive_chown(&base_dir, self.uid, self.gid);
+        util::recursive_chown(base_dir, self.uid, self.gid);
 
         log::info!(
             "[XunleiInstall] chown: {}, UID:{}, GID:{}",
@@ -202,7 +213,10 @@ impl XunleiInstall {
                 self.auth_user.clone().unwrap_or_default(),
                 self.auth_password.clone().unwrap_or_default()
             ),
-            false => "".to_string(),
+            false => match &self.auth_file {
+                Some(auth_file) => format!("--auth-file {}", auth_file.display()),
+                None => "".to_string(),
+            },
         };
 
         let debug = match self.debug {
@@ -250,11 +264,232 @@ impl XunleiInstall {
 
 impl Running for XunleiInstall {
     fn run(self) -> anyhow::Result<()> {
+        // The directories are handed over to --uid/--gid, which fails mid-install without CAP_CHOWN
+        if self.uid != nix::unistd::geteuid().as_raw()
+            || self.gid != nix::unistd::getegid().as_raw()
+        {
+            util::check_capabilities(&[(
+                util::Capability::Chown,
+                format!("chown the xunlei directories to {}:{}", self.uid, self.gid),
+                "or pass --uid/--gid of the current user",
+            )])
+            .context(ErrorKind::Permission)?;
+        }
         self.config()?;
         self.systemd(self.install()?)
     }
 }
 
+// Common download locations of NAS systems, the first existing one is suggested
+const MEDIA_DIRS: [&str; 5] = [
+    "/volume1/downloads",
+    "/mnt/downloads",
+    "/srv/downloads",
+    "/media/downloads",
+    "/downloads",
+];
+const WIZARD_ENV_FILE: &str = "install.env";
+
+// Ask for the install settings with detected defaults, and save them as XUNLEI_* variables
+pub fn first_run_wizard(config: &mut Config) -> anyhow::Result<()> {
+    if nix::unistd::isatty(nix::libc::STDIN_FILENO)
+        .unwrap_or(false)
+        .not()
+    {
+        return Err(anyhow::anyhow!(
+            "[XunleiInstall] --first-run-wizard needs an interactive terminal"
+        )
+        .context(ErrorKind::Config));
+    }
+    eprintln!("Xunlei first run setup, press Enter to keep the [default]");
+
+    // A hostname that doesn't resolve yet leaves every port suggestable
+    let host = config.host.resolve().ok();
+    let free =
+        |port: u16| host.is_none_or(|host| std::net::TcpListener::bind((host, port)).is_ok());
+    let suggested_port = (config.port..config.port.saturating_add(100))
+        .find(|port| free(*port))
+        .unwrap_or(config.port);
+    config.port = prompt("Panel port", suggested_port.to_string(), |s| {
+        let port = crate::parser_port_in_range(s)?;
+        match free(port) {
+            true => Ok(port),
+            false => anyhow::bail!("Port {} is already in use", port),
+        }
+    })?;
+
+    let uid = config.uid.unwrap_or(nix::unistd::getuid().into());
+    config.uid = Some(prompt(
+        "UID of the xunlei processes",
+        uid.to_string(),
+        |s| {
+            s.parse::<u32>()
+                .map_err(|_| anyhow::anyhow!("`{}` isn't a UID", s))
+        },
+    )?);
+    let gid = config.gid.unwrap_or(nix::unistd::getgid().into());
+    config.gid = Some(prompt(
+        "GID of the xunlei processes",
+        gid.to_string(),
+        |s| {
+            s.parse::<u32>()
+                .map_err(|_| anyhow::anyhow!("`{}` isn't a GID", s))
+        },
+    )?);
+
+    config.config_path = prompt(
+        "Config directory",
+        config.config_path.display().to_string(),
+        wizard_dir,
+    )?;
+    let download_path = MEDIA_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_dir())
+        .unwrap_or_else(|| config.download_path.clone());
+    config.download_path = prompt(
+        "Download directory",
+        download_path.display().to_string(),
+        wizard_dir,
+    )?;
+    config.mount_bind_download_path = prompt(
+        "Bind mount of the download directory",
+        config.mount_bind_download_path.display().to_string(),
+        wizard_dir,
+    )?;
+
+    let user = prompt(
+        "Panel username, empty disables authentication",
+        String::new(),
+        |s| Ok(s.to_owned()),
+    )?;
+    if user.is_empty() {
+        config.auth_user = None;
+        config.auth_password = None;
+    } else {
+        let password = loop {
+            let password = util::read_password("Panel password: ")?;
+            if password.is_empty() {
+                eprintln!("  Password must not be empty");
+            } else if util::read_password("Repeat the password: ")? != password {
+                eprintln!("  Passwords don't match");
+            } else {
+                break password;
+            }
+        };
+        config.auth_user = Some(user);
+        config.auth_password = Some(password);
+        config.auth_file = None;
+    }
+
+    let path = config.config_path.join(WIZARD_ENV_FILE);
+    write_wizard_env(config, &path)?;
+    eprintln!(
+        "Saved to {}, reuse it with: set -a; . {}; set +a; xunlei install",
+        path.display(),
+        path.display()
+    );
+    Ok(())
+}
+
+// Prompt until the answer parses, an empty answer takes the default
+fn prompt<T>(
+    label: &str,
+    default: String,
+    parse: impl Fn(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    loop {
+        eprint!("{} [{}]: ", label, default);
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Err(anyhow::anyhow!("[XunleiInstall] Setup aborted").context(ErrorKind::Config));
+        }
+        let answer = match line.trim() {
+            "" => default.as_str(),
+            answer => answer,
+        };
+        match parse(answer) {
+            Ok(value) => return Ok(value),
+            Err(e) => eprintln!("  {}", e),
+        }
+    }
+}
+
+// An absolute path that is a directory or can still be created as one
+fn wizard_dir(s: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(s);
+    if path.is_absolute().not() {
+        anyhow::bail!("`{}` isn't an absolute path", s)
+    }
+    if path.exists() && path.is_dir().not() {
+        anyhow::bail!("{} exists and isn't a directory", path.display())
+    }
+    Ok(path)
+}
+
+// The answers as an env file, readable by the owner only since it may hold the password
+fn write_wizard_env(config: &Config, path: &Path) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut content = String::from("# Written by xunlei install --first-run-wizard\n");
+    let mut entries = vec![
+        ("XUNLEI_HOST", config.host.to_string()),
+        ("XUNLEI_PORT", config.port.to_string()),
+        (
+            "XUNLEI_CONFIG_PATH",
+            config.config_path.display().to_string(),
+        ),
+        (
+            "XUNLEI_DOWNLOAD_PATH",
+            config.download_path.display().to_string(),
+        ),
+        (
+            "XUNLEI_MOUNT_BIND_DOWNLOAD_PATH",
+            config.mount_bind_download_path.display().to_string(),
+        ),
+    ];
+    entries.extend(config.uid.map(|uid| ("XUNLEI_UID", uid.to_string())));
+    entries.extend(config.gid.map(|gid| ("XUNLEI_GID", gid.to_string())));
+    entries.extend(
+        config
+            .auth_user
+            .clone()
+            .map(|user| ("XUNLEI_AUTH_USER", user)),
+    );
+    entries.extend(
+        config
+            .auth_password
+            .clone()
+            .map(|password| ("XUNLEI_AUTH_PASSWORD", password)),
+    );
+    for (key, value) in entries {
+        // Single quoted for the shell, a quote inside is closed, escaped and reopened
+        content.push_str(&format!("{}='{}'\n", key, value.replace('\'', r"'\''")));
+    }
+
+    std::fs::create_dir_all(&config.config_path)
+        .and_then(|_| {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(path)
+        })
+        // An existing file keeps its mode on open
+        .and_then(|mut file| {
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            file.write_all(content.as_bytes())
+        })
+        .context(format!(
+            "[XunleiInstall] Failed to write {}",
+            path.display()
+        ))
+        .context(ErrorKind::Config)?;
+    Ok(())
+}
+
 pub struct XunleiUninstall {
     clear: bool,
 }
@@ -317,6 +552,191 @@ impl From<bool> for XunleiUninstall {
     }
 }
 
+pub struct XunleiUpgrade {
+    asset_file: Option<PathBuf>,
+    uid: u32,
+    gid: u32,
+}
+
+impl XunleiUpgrade {
+    fn upgrade(&self, xunlei: &impl XunleiAsset) -> anyhow::Result<()> {
+        log::info!("[XunleiUpgrade] Upgrading in progress");
+        let target_dir = PathBuf::from(env::SYNOPKG_PKGDEST);
+        let backup_dir = PathBuf::from(env::SYNOPKG_BACKUP);
+
+        if backup_dir.exists() {
+            std::fs::remove_dir_all(&backup_dir)?;
+        }
+        util::create_dir_all(&backup_dir, 0o755)?;
+
+        // Stage every new file first, a broken asset leaves the installation untouched
+        let files = xunlei.iter().context(ErrorKind::Asset)?;
+        for filename in files.iter() {
+            if let Err(e) = self.stage(xunlei, &target_dir, filename) {
+                log::error!("[XunleiUpgrade] Failed to stage {}", filename);
+                Self::remove_staged(&target_dir, &files);
+                return Err(e);
+            }
+        }
+
+        let mut swapped = Vec::new();
+        for filename in files.iter() {
+            if let Err(e) = Self::swap(&target_dir, &backup_dir, filename) {
+                log::error!(
+                    "[XunleiUpgrade] Failed to upgrade {}, rolling back",
+                    filename
+                );
+                Self::rollback(&target_dir, &backup_dir, &swapped);
+                Self::remove_staged(&target_dir, &files);
+                return Err(e);
+            }
+            swapped.push(filename.as_str());
+        }
+
+        log::info!(
+            "[XunleiUpgrade] Previous version backed up to: {}",
+            backup_dir.display()
+        );
+        log::info!("[XunleiUpgrade] Upgrade completed");
+        Ok(())
+    }
+
+    fn run_upgrade(&self, xunlei: impl XunleiAsset) -> anyhow::Result<()> {
+        if let Ok(version) = xunlei.version() {
+            log::info!("[XunleiUpgrade] Upgrade to version: {}", version.trim());
+        }
+
+        let systemd = Systemd::support();
+        if systemd {
+            Systemd::systemctl(["stop", env::APP_NAME])?;
+        }
+        // Without systemd there is nothing to stop it with, don't swap files under it
+        let result = match Self::running_backend() {
+            Some(pid) => Err(anyhow::anyhow!(
+                "xunlei is still running (PID {}), stop the launcher before upgrading",
+                pid
+            )
+            .context(ErrorKind::Config)),
+            None => self.upgrade(&xunlei),
+        };
+
+        // Restart with either the new, the rolled back or the untouched version
+        if systemd {
+            if let Err(e) = Systemd::systemctl(["start", env::APP_NAME]) {
+                match result {
+                    Ok(_) => return Err(e),
+                    Err(_) => log::error!("[XunleiUpgrade] Failed to restart: {}", e),
+                }
+            }
+        }
+        result
+    }
+
+    // Write the new file next to the installed one, with the owner of the installation
+    fn stage(
+        &self,
+        xunlei: &impl XunleiAsset,
+        target_dir: &Path,
+        filename: &str,
+    ) -> anyhow::Result<()> {
+        let data = xunlei
+            .get(filename)
+            .context("Read data failure")
+            .context(ErrorKind::Asset)?;
+        let staged_filepath = target_dir.join(format!(".{}.upgrade", filename));
+        util::write_file(&staged_filepath, data, 0o755)?;
+        util::chown(&staged_filepath, self.uid, self.gid)?;
+        Ok(())
+    }
+
+    fn remove_staged(target_dir: &Path, files: &[String]) {
+        for filename in files {
+            let _ = std::fs::remove_file(target_dir.join(format!(".{}.upgrade", filename)));
+        }
+    }
+
+    // The PID of the backend when its PID file names a live process
+    fn running_backend() -> Option<i32> {
+        let pid = std::fs::read_to_string(env::PID_FILE)
+            .ok()?
+            .trim()
+            .parse::<i32>()
+            .ok()
+            .filter(|pid| *pid > 0)?;
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None)
+            .ok()
+            .map(|_| pid)
+    }
+
+    // Back up the current file, then atomically replace it with the staged one
+    fn swap(target_dir: &Path, backup_dir: &Path, filename: &str) -> anyhow::Result<()> {
+        let target_filepath = target_dir.join(filename);
+        if target_filepath.exists() {
+            std::fs::copy(&target_filepath, backup_dir.join(filename))
+                .context(format!("Failed to back up: {}", target_filepath.display()))?;
+        }
+        std::fs::rename(
+            target_dir.join(format!(".{}.upgrade", filename)),
+            &target_filepath,
+        )
+        .context(format!("Failed to replace: {}", target_filepath.display()))?;
+        log::info!("[XunleiUpgrade] Upgrade: {}", target_filepath.display());
+        Ok(())
+    }
+
+    fn rollback(target_dir: &Path, backup_dir: &Path, swapped: &[&str]) {
+        for filename in swapped {
+            let target_filepath = target_dir.join(filename);
+            let backup_filepath = backup_dir.join(filename);
+            let res = match backup_filepath.exists() {
+                true => std::fs::rename(&backup_filepath, &target_filepath),
+                false => std::fs::remove_file(&target_filepath),
+            };
+            match res {
+                Ok(_) => log::info!("[XunleiUpgrade] Rollback: {}", target_filepath.display()),
+                Err(e) => log::error!(
+                    "[XunleiUpgrade] Rollback {} failed: {}",
+                    target_filepath.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+impl Running for XunleiUpgrade {
+    fn run(self) -> anyhow::Result<()> {
+        if Path::new(env::SYNOPKG_PKGDEST).is_dir().not() {
+            return Err(
+                anyhow::anyhow!("xunlei is not installed, please install it first")
+                    .context(ErrorKind::Config),
+            );
+        }
+
+        // Prepare the new assets before stopping, to keep the downtime short
+        match &self.asset_file {
+            Some(asset_file) => self
+                .run_upgrade(xunlei_asset::asset_from_file(asset_file).context(ErrorKind::Asset)?),
+            None => self.run_upgrade(xunlei_asset::asset().context(ErrorKind::Asset)?),
+        }
+    }
+}
+
+impl From<(Option<PathBuf>, Option<u32>, Option<u32>)> for XunleiUpgrade {
+    fn from(value: (Option<PathBuf>, Option<u32>, Option<u32>)) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        // The new files keep the owner of the installed ones
+        let owner = std::fs::metadata(env::SYNOPKG_PKGDEST)
+            .map(|meta| (meta.uid(), meta.gid()))
+            .unwrap_or((nix::unistd::getuid().into(), nix::unistd::getgid().into()));
+        Self {
+            asset_file: value.0,
+            uid: value.1.unwrap_or(owner.0),
+            gid: value.2.unwrap_or(owner.1),
+        }
+    }
+}
+
 struct Systemd;
 
 impl Systemd {
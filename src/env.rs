@@ -11,7 +11,26 @@ pub const SYNOPKG_DSM_VERSION_MINOR: &str = "0";
 pub const SYNOPKG_DSM_VERSION_BUILD: &str = "1";
 pub const SYNOPKG_PKGNAME: &str = "pan-xunlei-com";
 pub const SYNOPKG_PKGBASE: &str = "/var/packages/pan-xunlei-com";
+pub const SYNOPKG_CHROOT: &str = "/var/packages/pan-xunlei-com/root";
+// Host paths the backend needs inside --chroot, besides the package, config and download directories
+pub const CHROOT_HOST_PATHS: [&str; 14] = [
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    "/dev",
+    "/proc",
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/localtime",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/ssl",
+    "/etc/pki",
+];
 pub const SYNOPKG_PKGDEST: &str = "/var/packages/pan-xunlei-com/target";
+pub const SYNOPKG_BACKUP: &str = "/var/packages/pan-xunlei-com/backup";
 pub const SYNOPKG_VAR: &str = "/var/packages/pan-xunlei-com/target/var/";
 pub const SYNOPKG_HOST: &str = "/var/packages/pan-xunlei-com/target/host";
 #[cfg(all(target_os = "linux", target_env = "musl"))]
@@ -33,8 +52,13 @@ pub const LAUNCH_PID_FILE: &str =
     "/var/packages/pan-xunlei-com/target/var/pan-xunlei-com-launcher.pid";
 pub const LAUNCH_LOG_FILE: &str =
     "/var/packages/pan-xunlei-com/target/var/pan-xunlei-com-launcher.log";
+pub const SESSIONS_FILE: &str =
+    "/var/packages/pan-xunlei-com/target/var/pan-xunlei-com-sessions.json";
 pub const INST_LOG: &str = "/var/packages/pan-xunlei-com/target/var/pan-xunlei-com_install.log";
 pub const SYNOPKG_WEB_UI_HOME: &str = "/webman/3rdparty/pan-xunlei-com/index.cgi/";
+pub const DEFAULT_PROBE_URL: &str = "/webman/login.cgi";
 pub const DEFAULT_DOWNLOAD_PATH: &str = "/opt/xunlei/downloads";
 pub const DEFAULT_BIND_DOWNLOAD_PATH: &str = "/xunlei";
 pub const DEFAULT_CONFIG_PATH: &str = "/opt/xunlei";
+pub const DEFAULT_HOST: &str = "0.0.0.0";
+pub const DEFAULT_PORT: &str = "5055";
@@ -0,0 +1,71 @@
+// Process exit codes, one per failure category:
+//
+// | Code | Category                                  |
+// |------|-------------------------------------------|
+// | 0    | Success                                   |
+// | 1    | Unclassified failure                      |
+// | 2    | Configuration / validation error          |
+// | 3    | Permission or mount error                 |
+// | 4    | Listen port already in use                |
+// | 5    | Missing xunlei assets                     |
+// | 6    | Backend failed to stay up                 |
+//
+// Command line parse errors are reported by clap, which also exits with 2.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Config,
+    Permission,
+    PortInUse,
+    Asset,
+    Backend,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Config => 2,
+            ErrorKind::Permission => 3,
+            ErrorKind::PortInUse => 4,
+            ErrorKind::Asset => 5,
+            ErrorKind::Backend => 6,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Config => f.write_str("configuration error"),
+            ErrorKind::Permission => f.write_str("permission error"),
+            ErrorKind::PortInUse => f.write_str("port already in use"),
+            ErrorKind::Asset => f.write_str("missing xunlei assets"),
+            ErrorKind::Backend => f.write_str("backend failed to stay up"),
+        }
+    }
+}
+
+impl std::error::Error for ErrorKind {}
+
+// Resolve the exit code of an error, an explicit `ErrorKind` context wins over
+// the errno found in the source chain
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    if let Some(kind) = err.downcast_ref::<ErrorKind>() {
+        return kind.exit_code();
+    }
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            match e.kind() {
+                std::io::ErrorKind::AddrInUse => return ErrorKind::PortInUse.exit_code(),
+                std::io::ErrorKind::PermissionDenied => return ErrorKind::Permission.exit_code(),
+                _ => {}
+            }
+        }
+        if let Some(errno) = cause.downcast_ref::<nix::errno::Errno>() {
+            if matches!(errno, nix::errno::Errno::EPERM | nix::errno::Errno::EACCES) {
+                return ErrorKind::Permission.exit_code();
+            }
+        }
+    }
+    1
+}
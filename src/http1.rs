@@ -0,0 +1,563 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::ops::Not;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::listener::{self, BodyLimit, IDLE_TIMEOUT};
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 64;
+// Wait for the next request on a kept alive connection, short since each holds a worker
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The stream the requests of a connection are read from and answered on
+pub trait Connection: Read + Write + Send + 'static {
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()>;
+    // End the connection after the last response
+    fn close(&mut self) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+type Shared<C> = Arc<Mutex<BufReader<C>>>;
+
+// Serve the HTTP/1.1 requests of one connection until it closes, goes idle or can't
+// be reused, streaming each request body to the handler as it reads it
+pub fn serve_requests<C, F>(
+    connection: C,
+    remote_addr: SocketAddr,
+    https: bool,
+    body_limit: &BodyLimit,
+    handler: &F,
+) -> anyhow::Result<()>
+where
+    C: Connection,
+    F: Fn(&rouille::Request) -> rouille::Response,
+{
+    let connection: Shared<C> = Arc::new(Mutex::new(BufReader::new(connection)));
+    let mut wait = IDLE_TIMEOUT;
+    loop {
+        let head = match read_head(&mut connection.lock().unwrap(), Instant::now() + wait)? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+        wait = KEEP_ALIVE_TIMEOUT;
+        connection
+            .lock()
+            .unwrap()
+            .get_ref()
+            .set_read_timeout(body_limit.timeout())?;
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        if parsed.parse(&head)?.is_partial() {
+            anyhow::bail!("incomplete request head")
+        }
+        let method = parsed.method.unwrap_or("GET").to_owned();
+        let url = parsed.path.unwrap_or("/").to_owned();
+        let http10 = parsed.version == Some(0);
+        let headers = parsed
+            .headers
+            .iter()
+            .map(|h| {
+                (
+                    h.name.to_owned(),
+                    String::from_utf8_lossy(h.value).into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        let keep_alive = match header("Connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => http10.not(),
+        };
+
+        let expect_continue =
+            header("Expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+        let chunked = header("Transfer-Encoding").is_some_and(|v| {
+            v.rsplit(',')
+                .next()
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case("chunked"))
+        });
+        let limit = body_limit.for_url(&url);
+        let framing = match chunked {
+            true => Framing::Chunked(0),
+            false => Framing::Length(
+                header("Content-Length")
+                    .map(|v| v.trim().parse::<u64>())
+                    .transpose()
+                    .context("invalid Content-Length")?
+                    .unwrap_or(0),
+            ),
+        };
+        // The rest of an oversized body is never read, so the connection can't be reused
+        if matches!(framing, Framing::Length(len) if len > limit) {
+            let mut stream = connection.lock().unwrap();
+            return refuse_body(stream.get_mut(), &method, &url, limit);
+        }
+
+        let mut state = BodyState::new(framing, limit);
+        // Only once the handler asks for the body, so that oversized bodies and requests
+        // answered without their body, like those failing authentication, are never sent
+        state.continue_pending = expect_continue && state.complete().not();
+        let state = Arc::new(Mutex::new(state));
+        let answered = Arc::new(AtomicBool::new(false));
+        let body = Body {
+            connection: connection.clone(),
+            state: state.clone(),
+            answered: answered.clone(),
+        };
+        let request = match https {
+            true => {
+                rouille::Request::fake_https_from(remote_addr, &method, &url, headers, Vec::new())
+            }
+            false => {
+                rouille::Request::fake_http_from(remote_addr, &method, &url, headers, Vec::new())
+            }
+        };
+        let response = listener::with_body(&request, Box::new(body), || {
+            std::panic::catch_unwind(AssertUnwindSafe(|| handler(&request)))
+        })
+        .unwrap_or_else(|_| {
+            rouille::Response::html(
+                "<h1>Internal Server Error</h1><p>An internal error has occurred on the server.</p>",
+            )
+            .with_status_code(500)
+        });
+        // A body read in progress holds the state, it can't have gone over the limit before
+        if state.try_lock().is_ok_and(|state| state.over_limit) {
+            answered.store(true, Ordering::SeqCst);
+            let mut stream = connection.lock().unwrap();
+            return refuse_body(stream.get_mut(), &method, &url, limit);
+        }
+        // The handler may still be reading the body while the response streams out, as a CGI
+        // answering before it consumed its input does. An unread rest of the body would be
+        // taken for the next request, which is known upfront once nothing reads it anymore
+        let unread = Arc::strong_count(&state) == 1 && state.lock().unwrap().complete().not();
+        // No interim response once the final one started, a read in progress already sent it
+        if let Ok(mut state) = state.try_lock() {
+            state.continue_pending = false;
+        }
+        write_response(
+            &mut Writer(&connection),
+            response,
+            &method,
+            keep_alive && unread.not(),
+        )?;
+        // Reads of the body still going on past the response fail from here on
+        answered.store(true, Ordering::SeqCst);
+        let complete = state.lock().unwrap().complete();
+        if keep_alive.not() || complete.not() {
+            connection.lock().unwrap().get_mut().close()?;
+            return Ok(());
+        }
+    }
+}
+
+// Writes to the shared connection, which it only holds for each write so that the body
+// can be read in between
+struct Writer<'a, C>(&'a Shared<C>);
+
+impl<C: Connection> Write for Writer<'_, C> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().get_mut().flush()
+    }
+}
+
+// Answer 413 and close, the rest of the body stays unread
+fn refuse_body(
+    stream: &mut impl Connection,
+    method: &str,
+    url: &str,
+    limit: u64,
+) -> anyhow::Result<()> {
+    log::debug!("[Http1] Request body of {} over {} bytes", url, limit);
+    let response = rouille::Response::text("Request body too large").with_status_code(413);
+    write_response(stream, response, method, false)?;
+    stream.close()?;
+    Ok(())
+}
+
+// Read up to the blank line ending the request head before the deadline, None when the
+// client closed the connection
+fn read_head<C: Connection>(
+    stream: &mut BufReader<C>,
+    deadline: Instant,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    loop {
+        // Every read only gets the time left, so that a trickled head can't outlast it
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            anyhow::bail!("request head timed out")
+        }
+        stream.get_ref().set_read_timeout(left)?;
+        let available = stream.fill_buf()?;
+        if available.is_empty() {
+            return match head.is_empty() {
+                true => Ok(None),
+                false => Err(anyhow::anyhow!("connection closed in request head")),
+            };
+        }
+        let (used, line_end) = match available.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), false),
+        };
+        head.extend_from_slice(&available[..used]);
+        stream.consume(used);
+        if line_end {
+            // Tolerate empty lines before the request line
+            if head == b"\r\n" || head == b"\n" {
+                head.clear();
+                continue;
+            }
+            if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+                return Ok(Some(head));
+            }
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            anyhow::bail!("request head too large")
+        }
+    }
+}
+
+// How the rest of a request body is delimited on the connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    // Bytes left
+    Length(u64),
+    // Bytes left in the current chunk, 0 before a chunk size line
+    Chunked(u64),
+    Done,
+}
+
+struct BodyState {
+    framing: Framing,
+    // Bytes the chunks may still add up to
+    left: u64,
+    over_limit: bool,
+    // The client waits for an interim 100 Continue before it sends the body
+    continue_pending: bool,
+}
+
+impl BodyState {
+    fn new(framing: Framing, limit: u64) -> Self {
+        Self {
+            framing,
+            left: limit,
+            over_limit: false,
+            continue_pending: false,
+        }
+    }
+
+    fn complete(&self) -> bool {
+        matches!(self.framing, Framing::Done | Framing::Length(0))
+    }
+
+    // Read the next body bytes off `stream`, Ok(0) once the body is complete
+    fn read(&mut self, stream: &mut impl BufRead, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let left = match self.framing {
+                Framing::Done | Framing::Length(0) => return Ok(0),
+                Framing::Chunked(0) => {
+                    let mut line = String::new();
+                    stream.read_line(&mut line)?;
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size = u64::from_str_radix(size, 16).map_err(|_| {
+                        Error::new(ErrorKind::InvalidData, "[Http1] Invalid chunk size")
+                    })?;
+                    if size == 0 {
+                        // Skip the trailers
+                        loop {
+                            line.clear();
+                            if stream.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        self.framing = Framing::Done;
+                        return Ok(0);
+                    }
+                    // Refused before anything of the chunk is read
+                    if size > self.left {
+                        self.over_limit = true;
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "[Http1] Request body too large",
+                        ));
+                    }
+                    self.left -= size;
+                    self.framing = Framing::Chunked(size);
+                    continue;
+                }
+                Framing::Length(left) | Framing::Chunked(left) => left,
+            };
+            let len = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+            let read = stream.read(&mut buf[..len])?;
+            if read == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "[Http1] Connection closed in request body",
+                ));
+            }
+            let left = left - read as u64;
+            self.framing = match self.framing {
+                Framing::Chunked(_) if left == 0 => {
+                    // The line break ending the chunk
+                    let mut line = String::new();
+                    stream.read_line(&mut line)?;
+                    Framing::Chunked(0)
+                }
+                Framing::Chunked(_) => Framing::Chunked(left),
+                _ => Framing::Length(left),
+            };
+            return Ok(read);
+        }
+    }
+}
+
+// The request body handed to the handler, read off the connection as it asks for it
+struct Body<C> {
+    connection: Shared<C>,
+    state: Arc<Mutex<BodyState>>,
+    // Set once the response is out, the connection then only carries the next request
+    answered: Arc<AtomicBool>,
+}
+
+impl<C: Connection> Read for Body<C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if self.answered.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "[Http1] The request was already answered",
+            ));
+        }
+        let mut connection = self.connection.lock().unwrap();
+        if state.continue_pending {
+            state.continue_pending = false;
+            connection
+                .get_mut()
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            connection.get_mut().flush()?;
+        }
+        state.read(&mut *connection, buf)
+    }
+}
+
+fn write_response(
+    stream: &mut impl Write,
+    response: rouille::Response,
+    method: &str,
+    keep_alive: bool,
+) -> anyhow::Result<()> {
+    let status = tiny_http::StatusCode(response.status_code);
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status_code,
+        status.default_reason_phrase()
+    );
+    let mut has_date = false;
+    for (key, value) in &response.headers {
+        if ["Content-Length", "Transfer-Encoding", "Connection"]
+            .iter()
+            .any(|h| key.eq_ignore_ascii_case(h))
+        {
+            continue;
+        }
+        has_date |= key.eq_ignore_ascii_case("Date");
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    if has_date.not() {
+        head.push_str(&format!(
+            "Date: {}\r\n",
+            chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT")
+        ));
+    }
+
+    let (mut data, len) = response.data.into_reader_and_size();
+    let chunked = len.is_none();
+    match len {
+        Some(len) => head.push_str(&format!("Content-Length: {}\r\n", len)),
+        None => head.push_str("Transfer-Encoding: chunked\r\n"),
+    }
+    if keep_alive.not() {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+
+    let no_body = method.eq_ignore_ascii_case("HEAD")
+        || matches!(response.status_code, 100..=199 | 204 | 304);
+    if no_body.not() {
+        match chunked {
+            true => {
+                let mut buf = vec![0; 8192];
+                loop {
+                    let read = data.read(&mut buf)?;
+                    if read == 0 {
+                        break;
+                    }
+                    stream.write_all(format!("{:x}\r\n", read).as_bytes())?;
+                    stream.write_all(&buf[..read])?;
+                    stream.write_all(b"\r\n")?;
+                    stream.flush()?;
+                }
+                stream.write_all(b"0\r\n\r\n")?;
+            }
+            false => {
+                std::io::copy(&mut data, stream)?;
+            }
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Read a whole chunked body the way the handler would, None once it goes over `limit`
+    fn read_chunked(mut stream: &[u8], limit: u64) -> Option<Vec<u8>> {
+        let mut state = BodyState::new(Framing::Chunked(0), limit);
+        let mut body = Vec::new();
+        let mut buf = [0; 2];
+        loop {
+            match state.read(&mut stream, &mut buf) {
+                Ok(0) => return Some(body),
+                Ok(read) => body.extend_from_slice(&buf[..read]),
+                Err(_) if state.over_limit => return None,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn read_chunked_body() {
+        let stream = &b"3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\nTrailer: x\r\n\r\n"[..];
+        let body = read_chunked(stream, 5);
+        assert_eq!(body.as_deref(), Some(&b"abcde"[..]));
+    }
+
+    // A client connection sending `input`, the bytes written back are kept in `output`
+    struct MockConnection {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn set_read_timeout(&self, _timeout: Duration) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Serve the requests of `input` with a body limit of 10 bytes, the bytes written back
+    fn serve<F>(input: &[u8], handler: F) -> String
+    where
+        F: Fn(&rouille::Request, &Mutex<Vec<u8>>) -> rouille::Response,
+    {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let connection = MockConnection {
+            input: std::io::Cursor::new(input.to_vec()),
+            output: output.clone(),
+        };
+        let remote_addr = "127.0.0.1:1234".parse().unwrap();
+        let body_limit = BodyLimit::new(10, 5, "");
+        serve_requests(connection, remote_addr, false, &body_limit, &|request| {
+            handler(request, &output)
+        })
+        .unwrap();
+        let output = output.lock().unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[test]
+    fn continue_on_body_read() {
+        let output = serve(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\nConnection: close\r\n\r\nhello",
+            |request, output| {
+                assert!(output.lock().unwrap().is_empty());
+                let mut body = String::new();
+                listener::take_body(request)
+                    .unwrap()
+                    .read_to_string(&mut body)
+                    .unwrap();
+                assert_eq!(&output.lock().unwrap()[..], b"HTTP/1.1 100 Continue\r\n\r\n");
+                rouille::Response::text(body)
+            },
+        );
+        assert!(output.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn no_continue_over_limit() {
+        let handled = std::cell::Cell::new(false);
+        let output = serve(
+            b"POST / HTTP/1.1\r\nContent-Length: 100\r\nExpect: 100-continue\r\n\r\n",
+            |_, _| {
+                handled.set(true);
+                rouille::Response::empty_204()
+            },
+        );
+        assert!(output.starts_with("HTTP/1.1 413 "));
+        assert!(output.contains("100 Continue").not());
+        assert!(handled.get().not());
+    }
+
+    #[test]
+    fn read_chunked_over_limit() {
+        let stream = &b"3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n"[..];
+        assert!(read_chunked(stream, 5).is_none());
+        // A huge chunk size is refused before any of it is read
+        let stream = &b"ffffffffffff\r\nabc"[..];
+        assert!(read_chunked(stream, 5).is_none());
+    }
+}
@@ -0,0 +1,339 @@
+use std::convert::Infallible;
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener};
+use std::ops::Not;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, StatusCode};
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+
+use crate::listener::{self, BodyLimit, IDLE_TIMEOUT};
+
+const BODY_CHUNK: usize = 16 * 1024;
+
+// Serves rouille handlers over HTTP/2 and HTTP/1.1, negotiated by ALPN with TLS
+// and by the HTTP/2 connection preface (prior knowledge) on plain connections.
+// The handlers run on a bounded pool of blocking threads, the same size as rouille's
+pub struct Server<F> {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    body_limit: Arc<BodyLimit>,
+    handler: Arc<AssertUnwindSafe<F>>,
+}
+
+impl<F> Server<F>
+where
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    pub fn from_listener(
+        listener: TcpListener,
+        config: Option<Arc<ServerConfig>>,
+        body_limit: BodyLimit,
+        handler: F,
+    ) -> anyhow::Result<Self> {
+        listener
+            .set_nonblocking(true)
+            .context("[Http2] Failed to set the listener non-blocking")?;
+        let tls = config.map(|config| {
+            let mut config = (*config).clone();
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            TlsAcceptor::from(Arc::new(config))
+        });
+        Ok(Self {
+            listener,
+            tls,
+            body_limit: Arc::new(body_limit),
+            handler: Arc::new(AssertUnwindSafe(handler)),
+        })
+    }
+
+    pub fn run(self) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("http2")
+            .max_blocking_threads(listener::pool_size())
+            .enable_io()
+            .enable_time()
+            .build()
+            .expect("[Http2] Failed to start the runtime");
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(self.listener)
+                .expect("[Http2] Failed to register the listener");
+            loop {
+                let (stream, remote_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::debug!("[Http2] Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let tls = self.tls.clone();
+                let body_limit = self.body_limit.clone();
+                let handler = self.handler.clone();
+                tokio::spawn(async move {
+                    let result = match tls {
+                        Some(acceptor) => {
+                            match tokio::time::timeout(IDLE_TIMEOUT, acceptor.accept(stream)).await
+                            {
+                                Ok(Ok(stream)) => {
+                                    serve_connection(stream, remote_addr, true, body_limit, handler)
+                                        .await
+                                }
+                                Ok(Err(e)) => Err(e.into()),
+                                Err(_) => Err(anyhow::anyhow!("TLS handshake timed out")),
+                            }
+                        }
+                        None => {
+                            serve_connection(stream, remote_addr, false, body_limit, handler).await
+                        }
+                    };
+                    if let Err(e) = result {
+                        log::debug!("[Http2] Connection closed: {}", e);
+                    }
+                });
+            }
+        })
+    }
+}
+
+async fn serve_connection<S, F>(
+    stream: S,
+    remote_addr: SocketAddr,
+    https: bool,
+    body_limit: Arc<BodyLimit>,
+    handler: Arc<AssertUnwindSafe<F>>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    let service = service_fn(move |request| {
+        let body_limit = body_limit.clone();
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(
+                process(request, remote_addr, https, &body_limit, handler)
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::debug!("[Http2] Failed to process request: {}", e);
+                        status_response(StatusCode::BAD_REQUEST)
+                    }),
+            )
+        }
+    });
+    Http::new()
+        .http1_header_read_timeout(IDLE_TIMEOUT)
+        .http2_keep_alive_interval(IDLE_TIMEOUT)
+        .http2_keep_alive_timeout(IDLE_TIMEOUT)
+        .serve_connection(stream, service)
+        .await?;
+    Ok(())
+}
+
+async fn process<F>(
+    request: hyper::Request<Body>,
+    remote_addr: SocketAddr,
+    https: bool,
+    body_limit: &BodyLimit,
+    handler: Arc<AssertUnwindSafe<F>>,
+) -> anyhow::Result<hyper::Response<Body>>
+where
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    let (parts, body) = request.into_parts();
+    let url = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| String::from("/"));
+    let limit = body_limit.for_url(&url);
+    // A declared length over the limit is refused without reading anything
+    if body.size_hint().lower() > limit {
+        return Ok(too_large(&url, limit));
+    }
+    let mut headers = parts
+        .headers
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.as_str().to_owned(),
+                String::from_utf8_lossy(v.as_bytes()).into_owned(),
+            )
+        })
+        .collect::<Vec<_>>();
+    // HTTP/2 carries the host in the :authority pseudo header, the handlers expect Host
+    if parts.headers.contains_key(hyper::header::HOST).not() {
+        if let Some(authority) = parts.uri.authority() {
+            headers.push((String::from("Host"), authority.to_string()));
+        }
+    }
+    let method = parts.method.to_string();
+
+    // The handlers block on the CGI, which must not stall the runtime workers
+    let runtime = tokio::runtime::Handle::current();
+    let (sender, response_body) = Body::channel();
+    let over_limit = Arc::new(AtomicBool::new(false));
+    let body = RequestBody {
+        body,
+        runtime: runtime.clone(),
+        timeout: body_limit.timeout(),
+        chunk: Bytes::new(),
+        left: limit,
+        over_limit: over_limit.clone(),
+    };
+    let head = tokio::task::spawn_blocking(move || {
+        let request = match https {
+            true => {
+                rouille::Request::fake_https_from(remote_addr, &method, &url, headers, Vec::new())
+            }
+            false => {
+                rouille::Request::fake_http_from(remote_addr, &method, &url, headers, Vec::new())
+            }
+        };
+        let response = listener::with_body(&request, Box::new(body), || {
+            std::panic::catch_unwind(AssertUnwindSafe(|| handler(&request)))
+        })
+        .unwrap_or_else(|_| {
+            rouille::Response::html(
+                "<h1>Internal Server Error</h1><p>An internal error has occurred on the server.</p>",
+            )
+            .with_status_code(500)
+        });
+        if over_limit.load(Ordering::SeqCst) {
+            return None;
+        }
+        let (data, len) = response.data.into_reader_and_size();
+        let head = (response.status_code, response.headers, len);
+        // Stream the body from its own blocking task, so that the head can be sent right away
+        let handle = runtime.clone();
+        runtime.spawn_blocking(move || send_body(data, sender, handle));
+        Some(head)
+    })
+    .await?;
+    let Some(head) = head else {
+        return Ok(too_large(parts.uri.path(), limit));
+    };
+
+    let (status_code, response_headers, len) = head;
+    let mut response = hyper::Response::new(response_body);
+    *response.status_mut() = StatusCode::from_u16(status_code)?;
+    for (key, value) in response_headers {
+        // Framing is up to hyper, and connection specific headers are invalid in HTTP/2
+        if [
+            "Content-Length",
+            "Transfer-Encoding",
+            "Connection",
+            "Keep-Alive",
+        ]
+        .iter()
+        .any(|h| key.eq_ignore_ascii_case(h))
+        {
+            continue;
+        }
+        if let (Ok(key), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            response.headers_mut().append(key, value);
+        }
+    }
+    if let Some(len) = len {
+        response
+            .headers_mut()
+            .insert(hyper::header::CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    Ok(response)
+}
+
+fn too_large(url: &str, limit: u64) -> hyper::Response<Body> {
+    log::debug!("[Http2] Request body of {} over {} bytes", url, limit);
+    let mut response = hyper::Response::new(Body::from("Request body too large"));
+    *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+    response
+}
+
+// The request body, read from the handler thread as it asks for it. Each chunk has to
+// arrive within the body timeout
+struct RequestBody {
+    body: Body,
+    runtime: tokio::runtime::Handle,
+    timeout: Duration,
+    chunk: Bytes,
+    left: u64,
+    over_limit: Arc<AtomicBool>,
+}
+
+impl Read for RequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        if self.chunk.is_empty() {
+            let chunk = self
+                .runtime
+                // The timer needs the runtime context, which a plain thread only has in there
+                .block_on(async { tokio::time::timeout(self.timeout, self.body.data()).await })
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "[Http2] Request body timed out"))?;
+            let chunk = match chunk {
+                None => return Ok(0),
+                Some(chunk) => chunk.map_err(Error::other)?,
+            };
+            if chunk.len() as u64 > self.left {
+                self.over_limit.store(true, Ordering::SeqCst);
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "[Http2] Request body too large",
+                ));
+            }
+            self.left -= chunk.len() as u64;
+            self.chunk = chunk;
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk[..len]);
+        self.chunk.advance(len);
+        Ok(len)
+    }
+}
+
+fn send_body(
+    mut data: Box<dyn Read + Send>,
+    mut sender: hyper::body::Sender,
+    runtime: tokio::runtime::Handle,
+) {
+    let mut buf = vec![0; BODY_CHUNK];
+    loop {
+        match data.read(&mut buf) {
+            Ok(0) => return,
+            Ok(read) => {
+                let chunk = Bytes::copy_from_slice(&buf[..read]);
+                // Fails once the client went away or stopped reading
+                let sent = runtime.block_on(async {
+                    tokio::time::timeout(IDLE_TIMEOUT, sender.send_data(chunk)).await
+                });
+                if !matches!(sent, Ok(Ok(()))) {
+                    sender.abort();
+                    return;
+                }
+            }
+            Err(e) => {
+                log::debug!("[Http2] Failed to read response body: {}", e);
+                sender.abort();
+                return;
+            }
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -1,20 +1,34 @@
-use nix::mount::MsFlags;
 use nix::unistd::Pid;
 use rouille::router;
 use rouille::Request;
 use rouille::Response;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::net::SocketAddr;
 use std::os::unix::process::CommandExt;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use signal_hook::iterator::Signals;
 
+use crate::cgroup::Cgroup;
+use crate::error::ErrorKind;
+use crate::listener::{self, ListenOptions, PanelServer};
+use crate::logs::LogRotation;
+use crate::mount::{self, BindMount, Chroot};
+use crate::session::{FileSessions, MemorySessions, Session, SessionStore, SESSION_LIFETIME};
+use crate::tls;
 use crate::util;
-use crate::{env, Config, Running};
+use crate::xunlei_asset;
+use crate::{
+    env, CgiCachePolicy, Config, IoprioClass, ListenHost, MaxSessionsPolicy, MountPropagation,
+    OnBackendFailure, Running, SessionStoreKind, TlsCipherPolicy, TlsVersion,
+};
 use std::{
-    io::Read,
+    borrow::Cow,
+    io::{Read, Seek},
     ops::Not,
     path::{Path, PathBuf},
     process::Stdio,
@@ -22,6 +36,46 @@ use std::{
 
 const HTML_LOGIN: &str = include_str!("static/login.html");
 const JS_SHA3: &str = include_str!("static/sha3.min.js");
+const HTML_ERROR: &str = include_str!("static/error.html");
+const BACKEND_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const BACKEND_RESTART_DELAY: Duration = Duration::from_secs(1);
+const BACKEND_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const CGI_SPAWN_BACKOFF: Duration = Duration::from_millis(50);
+// The --test-mode backend, it comes up like the real one by creating the socket given as $1
+const TEST_MODE_BACKEND: &str = "touch \"$1\"; trap 'exit 0' INT TERM; while :; do sleep 1; done";
+// ioprio_set(2) constants, which the libc crate doesn't provide
+const IOPRIO_WHO_PROCESS: nix::libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: nix::libc::c_int = 13;
+const IOPRIO_CLASS_RT: nix::libc::c_int = 1;
+const IOPRIO_CLASS_BE: nix::libc::c_int = 2;
+const IOPRIO_CLASS_IDLE: nix::libc::c_int = 3;
+const SESSION_COOKIE: &str = "XUNLEI_SID";
+// Set on the panel workers spawned by --workers
+const PANEL_WORKER_ENV: &str = "XUNLEI_PANEL_WORKER";
+const WORKER_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const CGI_STDERR_TAIL: usize = 4096;
+const CGI_EXIT_WAIT: Duration = Duration::from_secs(1);
+// Headers that only concern a single connection, never forwarded from the CGI output (RFC 7230 6.1)
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Transfer-Encoding",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+// Validators and freshness headers replaced when a CGI response must not be cached
+const CACHE_HEADERS: [&str; 5] = [
+    "Cache-Control",
+    "Expires",
+    "ETag",
+    "Last-Modified",
+    "Pragma",
+];
+const AUTH_HASH_PREFIX: &str = "sha3-512:";
+// The login page posts hex SHA3-512 digests, anything much longer isn't a login
+const MAX_LOGIN_FIELD: usize = 256;
 
 // hasher auth message
 fn hasher_auth_message(s: &str) -> String {
@@ -31,11 +85,257 @@ fn hasher_auth_message(s: &str) -> String {
     format!("{:x}", hasher.finalize())
 }
 
+// One auth file line, with the user and password hashed the way the login page posts them
+struct Credential {
+    name: String,
+    user: String,
+    password: String,
+}
+
+// Parse an auth file of USER:PASSWORD lines, the password may be given as printed by
+// `generate-hash`. Blank lines and lines starting with # are skipped. Any malformed line
+// or a file without credentials is an error
+fn load_auth_file(path: &Path) -> anyhow::Result<Vec<Credential>> {
+    let content = std::fs::read_to_string(path)
+        .context(format!("Failed to read auth file {}", path.display()))?;
+    let mut credentials = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let credential = line.split_once(':').and_then(|(user, password)| {
+            if user.is_empty() || password.is_empty() {
+                return None;
+            }
+            let password = match password.strip_prefix(AUTH_HASH_PREFIX) {
+                Some(hash) if hash.len() == 128 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    hash.to_ascii_lowercase()
+                }
+                Some(_) => return None,
+                None => hasher_auth_message(password),
+            };
+            Some(Credential {
+                name: user.to_owned(),
+                user: hasher_auth_message(user),
+                password,
+            })
+        });
+        match credential {
+            Some(credential) => credentials.push(credential),
+            _ => anyhow::bail!(
+                "Malformed auth file {} at line {}, expected USER:PASSWORD",
+                path.display(),
+                index + 1
+            ),
+        }
+    }
+    if credentials.is_empty() {
+        anyhow::bail!("Auth file {} has no credentials", path.display())
+    }
+    Ok(credentials)
+}
+
+// Read a password from stdin and print it hashed for an --auth-file line
+pub struct XunleiGenerateHash;
+
+impl Running for XunleiGenerateHash {
+    fn run(self) -> anyhow::Result<()> {
+        let password = util::read_password("Password: ")?;
+        if password.is_empty() {
+            return Err(anyhow::anyhow!("Password must not be empty").context(ErrorKind::Config));
+        }
+        println!("{}{}", AUTH_HASH_PREFIX, hasher_auth_message(&password));
+        Ok(())
+    }
+}
+
+pub struct XunleiDoctor {
+    launcher: XunleiLauncher,
+    json: bool,
+}
+
+impl From<(XunleiLauncher, bool)> for XunleiDoctor {
+    fn from(value: (XunleiLauncher, bool)) -> Self {
+        Self {
+            launcher: value.0,
+            json: value.1,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Doctor {
+    ok: bool,
+    checks: Vec<util::Check>,
+}
+
+impl std::fmt::Display for Doctor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "{}", check)?;
+        }
+        match self.ok {
+            true => writeln!(f, "All checks passed"),
+            false => writeln!(f, "Some checks failed"),
+        }
+    }
+}
+
+fn writable_check(name: &'static str, dir: &Path) -> util::Check {
+    match util::probe_writable(dir) {
+        Ok(()) => util::Check::ok(name, format!("{} is writable", dir.display())),
+        Err(err) => util::Check::failed(name, format!("{:#}", err)),
+    }
+}
+
+impl Running for XunleiDoctor {
+    fn run(self) -> anyhow::Result<()> {
+        let launcher = &self.launcher;
+        // One check per capability, so that each names its own operation
+        let mut checks = launcher
+            .required_capabilities()
+            .into_iter()
+            .map(|(capability, operation, hint)| {
+                match util::check_capabilities(&[(capability, operation.clone(), hint)]) {
+                    Ok(()) => util::Check::ok(capability.name(), operation),
+                    Err(_) => util::Check::failed(
+                        capability.name(),
+                        format!("missing to {} ({})", operation, hint),
+                    ),
+                }
+            })
+            .collect::<Vec<_>>();
+        checks.push(match Path::new(env::SYNOPKG_VAR).is_dir() {
+            true => writable_check("package var directory", Path::new(env::SYNOPKG_VAR)),
+            false => util::Check::failed(
+                "package var directory",
+                format!("{} is missing, install xunlei first", env::SYNOPKG_VAR),
+            ),
+        });
+        if launcher.panel_only.not() {
+            // The backend creates a missing config directory itself
+            checks.push(match launcher.config_path.is_dir() {
+                true => writable_check("config directory", &launcher.config_path),
+                false => util::Check::ok(
+                    "config directory",
+                    format!(
+                        "{} is missing, the backend creates it",
+                        launcher.config_path.display()
+                    ),
+                ),
+            });
+            checks.push(match launcher.download_path.is_dir() {
+                true => writable_check("download directory", &launcher.download_path),
+                false => util::Check::failed(
+                    "download directory",
+                    format!("{} is missing", launcher.download_path.display()),
+                ),
+            });
+            // A leftover mount is replaced at startup, so only an unreadable mount table fails
+            let target = &launcher.mount_bind_download_path;
+            checks.push(match target.exists() {
+                false => util::Check::ok(
+                    "download mount",
+                    format!("{} is created and mounted at startup", target.display()),
+                ),
+                true => match util::is_mounted(target) {
+                    Ok(true) => util::Check::ok(
+                        "download mount",
+                        format!("{} is mounted", target.display()),
+                    ),
+                    Ok(false) => util::Check::ok(
+                        "download mount",
+                        format!(
+                            "{} is not mounted, {} is mounted there at startup",
+                            target.display(),
+                            launcher.download_path.display()
+                        ),
+                    ),
+                    Err(err) => util::Check::failed("download mount", format!("{:#}", err)),
+                },
+            });
+        }
+        let doctor = Doctor {
+            ok: checks.iter().all(|check| check.ok),
+            checks,
+        };
+        util::print_report(&doctor, self.json)
+    }
+}
+
+pub struct XunleiEnv {
+    launcher: XunleiLauncher,
+    json: bool,
+}
+
+impl From<(XunleiLauncher, bool)> for XunleiEnv {
+    fn from(value: (XunleiLauncher, bool)) -> Self {
+        Self {
+            launcher: value.0,
+            json: value.1,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Environment {
+    envs: std::collections::BTreeMap<String, String>,
+}
+
+impl std::fmt::Display for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in &self.envs {
+            writeln!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Running for XunleiEnv {
+    fn run(self) -> anyhow::Result<()> {
+        let environment = Environment {
+            envs: self
+                .launcher
+                .envs()
+                .context(ErrorKind::Config)?
+                .into_iter()
+                .collect(),
+        };
+        util::print_report(&environment, self.json)
+    }
+}
+
+fn login_field(value: &str, name: &str) -> io::Result<()> {
+    match value.len() > MAX_LOGIN_FIELD {
+        true => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is longer than {} bytes", name, MAX_LOGIN_FIELD),
+        )),
+        false => Ok(()),
+    }
+}
+
+// The request body, streamed by the panel transports or carried by the request itself
+fn request_body(request: &Request) -> Option<Box<dyn Read + Send + '_>> {
+    match listener::take_body(request) {
+        Some(body) => Some(body),
+        None => request
+            .data()
+            .map(|body| Box::new(body) as Box<dyn Read + Send>),
+    }
+}
+
 #[derive(Clone)]
 pub struct XunleiLauncher {
+    auth_user_name: Option<String>,
     auth_user: Option<String>,
     auth_password: Option<String>,
-    host: std::net::IpAddr,
+    auth_file: Option<PathBuf>,
+    max_login_body: u64,
+    max_request_body: u64,
+    login_csp_nonce: bool,
+    host: ListenHost,
     port: u16,
     debug: bool,
     download_path: PathBuf,
@@ -43,10 +343,76 @@ pub struct XunleiLauncher {
     mount_bind_download_path: PathBuf,
     uid: u32,
     gid: u32,
+    // --uid/--gid unset while the launcher runs as root
+    root_by_default: bool,
+    allow_root: bool,
+    request_timeout: Option<Duration>,
+    cgroup_memory_max: Option<String>,
+    cgroup_cpu_max: Option<String>,
+    backend_env: Vec<(String, String)>,
+    backend_env_strict: bool,
+    env_override_file: Option<PathBuf>,
+    backend_working_dir: PathBuf,
+    chroot: bool,
+    post_start: Vec<String>,
+    post_start_strict: bool,
+    on_backend_ready: Option<String>,
+    ready_timeout: Option<Duration>,
+    ready_interval: Duration,
+    drain_on_shutdown: Option<String>,
+    drain_timeout: Duration,
+    on_shutdown: Option<String>,
+    backend_nice: Option<i32>,
+    backend_ioprio: Option<(IoprioClass, u8)>,
+    backend_cpu_affinity: Option<Vec<usize>>,
+    backend_log_rotation: Option<LogRotation>,
+    listen_options: ListenOptions,
+    #[cfg(feature = "http2")]
+    http2: bool,
+    backend_restart_max: u32,
+    backend_restart_window: Option<Duration>,
+    on_backend_failure: OnBackendFailure,
+    umount_on_exit: bool,
+    mount_wait: Duration,
+    mount_propagation: MountPropagation,
+    web_ui_home: String,
+    probe_url: String,
+    base_path: String,
+    allowed_hosts: Vec<String>,
+    trusted_proxies: Vec<std::net::IpAddr>,
+    external_url: Option<String>,
+    error_page: Option<PathBuf>,
+    cgi_working_dir: PathBuf,
+    cgi_cache_policy: CgiCachePolicy,
+    cgi_spawn_retries: u32,
+    cgi_buffer_size: usize,
+    tls_cert: Vec<(Option<String>, PathBuf)>,
+    tls_key: Vec<PathBuf>,
+    min_tls_version: TlsVersion,
+    tls_cipher_policy: TlsCipherPolicy,
+    session_prune_interval: Duration,
+    session_store: SessionStoreKind,
+    max_sessions: usize,
+    max_sessions_policy: MaxSessionsPolicy,
+    shutdown_timeout: Duration,
+    session_accounting: bool,
+    heartbeat_interval: Option<Duration>,
+    drain: Arc<Drain>,
+    stats: Arc<Stats>,
+    admin_token: Option<String>,
+    restart_backend: Arc<AtomicBool>,
+    panel_only: bool,
+    test_mode: bool,
+    backend_only: bool,
+    workers: u32,
 }
 
 impl From<(bool, Config)> for XunleiLauncher {
     fn from(value: (bool, Config)) -> Self {
+        // The marker must not leak into the backend or CGI processes
+        let worker = std::env::var_os(PANEL_WORKER_ENV).is_some();
+        std::env::remove_var(PANEL_WORKER_ENV);
+        let auth_user_name = value.1.auth_user.clone();
         let auth_user = value
             .1
             .auth_user
@@ -57,8 +423,13 @@ impl From<(bool, Config)> for XunleiLauncher {
             .auth_password
             .map(|auth_password| hasher_auth_message(auth_password.as_str()));
         Self {
+            auth_user_name,
             auth_user,
             auth_password,
+            auth_file: value.1.auth_file,
+            max_login_body: value.1.max_login_body as u64,
+            max_request_body: value.1.max_request_body,
+            login_csp_nonce: value.1.login_csp_nonce,
             host: value.1.host,
             port: value.1.port,
             download_path: value.1.download_path,
@@ -67,6 +438,110 @@ impl From<(bool, Config)> for XunleiLauncher {
             debug: value.0,
             uid: value.1.uid.unwrap_or(nix::unistd::getuid().into()),
             gid: value.1.gid.unwrap_or(nix::unistd::getgid().into()),
+            // An explicit --uid 0 asks for root, the GID it defaults to included
+            root_by_default: match value.1.uid {
+                Some(uid) => {
+                    uid != 0 && value.1.gid.is_none() && nix::unistd::getgid().as_raw() == 0
+                }
+                None => {
+                    nix::unistd::getuid().is_root()
+                        || (value.1.gid.is_none() && nix::unistd::getgid().as_raw() == 0)
+                }
+            },
+            allow_root: value.1.allow_root,
+            request_timeout: value.1.request_timeout.map(Duration::from_secs),
+            cgroup_memory_max: value.1.cgroup_memory_max,
+            cgroup_cpu_max: value.1.cgroup_cpu_max,
+            backend_env: value.1.backend_env,
+            backend_env_strict: value.1.backend_env_strict,
+            env_override_file: value.1.env_override_file,
+            backend_working_dir: value.1.backend_working_dir,
+            chroot: value.1.chroot,
+            post_start: value.1.post_start,
+            post_start_strict: value.1.post_start_strict,
+            on_backend_ready: value.1.on_backend_ready,
+            ready_timeout: match value.1.ready_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            ready_interval: Duration::from_millis(value.1.ready_interval),
+            drain_on_shutdown: value.1.drain_on_shutdown,
+            drain_timeout: Duration::from_secs(value.1.drain_timeout),
+            on_shutdown: value.1.on_shutdown,
+            backend_nice: value.1.backend_nice,
+            backend_cpu_affinity: value
+                .1
+                .backend_cpu_affinity
+                .map(|list| list.cpus().to_vec()),
+            backend_ioprio: value
+                .1
+                .backend_ioprio_class
+                .map(|class| (class, value.1.backend_ioprio_level)),
+            backend_log_rotation: value.1.rotate_backend_log.then(|| {
+                LogRotation::new(
+                    env::LAUNCH_LOG_FILE,
+                    Some(value.1.rotate_backend_log_size).filter(|size| *size > 0),
+                    match value.1.rotate_backend_log_interval {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    },
+                    value.1.rotate_backend_log_keep,
+                )
+            }),
+            listen_options: ListenOptions {
+                backlog: value.1.listen_backlog.map(|backlog| backlog as usize),
+                reuse_addr: value.1.reuse_addr,
+                reuse_port: value.1.reuse_port || value.1.workers > 1 || worker,
+                nodelay: value.1.tcp_nodelay,
+            },
+            #[cfg(feature = "http2")]
+            http2: value.1.http2,
+            backend_restart_max: value.1.backend_restart_max,
+            backend_restart_window: value.1.backend_restart_window.map(Duration::from_secs),
+            on_backend_failure: value.1.on_backend_failure,
+            umount_on_exit: value.1.no_mount_umount_on_exit.not(),
+            mount_wait: Duration::from_secs(value.1.mount_wait),
+            mount_propagation: value.1.mount_propagation,
+            web_ui_home: value.1.web_ui_home,
+            probe_url: value.1.probe_url,
+            base_path: value.1.base_path.unwrap_or_default(),
+            allowed_hosts: value.1.allowed_host,
+            trusted_proxies: value.1.trust_forwarded_host,
+            external_url: value.1.external_url,
+            error_page: value.1.error_page,
+            cgi_working_dir: value.1.cgi_working_dir,
+            cgi_cache_policy: value.1.cgi_cache_policy,
+            cgi_spawn_retries: value.1.cgi_spawn_retries,
+            cgi_buffer_size: value.1.cgi_buffer_size as usize,
+            tls_cert: value.1.tls_cert,
+            tls_key: value.1.tls_key,
+            min_tls_version: value.1.min_tls_version,
+            tls_cipher_policy: value.1.tls_cipher_policy,
+            session_prune_interval: Duration::from_secs(value.1.session_prune_interval),
+            // The panel processes of --workers share the logins through the file
+            session_store: match value.1.workers > 1 || worker {
+                true => SessionStoreKind::File,
+                false => value.1.session_store,
+            },
+            max_sessions: value.1.max_sessions as usize,
+            max_sessions_policy: value.1.max_sessions_policy,
+            shutdown_timeout: Duration::from_secs(value.1.shutdown_timeout),
+            session_accounting: value.1.session_accounting,
+            heartbeat_interval: match value.1.heartbeat_interval {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            drain: Arc::new(Drain::default()),
+            stats: Arc::new(Stats::default()),
+            admin_token: value.1.admin_token,
+            restart_backend: Arc::new(AtomicBool::new(false)),
+            panel_only: value.1.panel_only || worker,
+            test_mode: value.1.test_mode,
+            backend_only: value.1.backend_only,
+            workers: match worker {
+                true => 1,
+                false => value.1.workers,
+            },
         }
     }
 }
@@ -114,9 +589,15 @@ impl XunleiLauncher {
             String::from("SYNOPKG_PKGNAME"),
             String::from(env::SYNOPKG_PKGNAME),
         );
-        envs.insert(String::from("SVC_CWD"), String::from(env::SYNOPKG_PKGDEST));
+        envs.insert(
+            String::from("SVC_CWD"),
+            self.backend_working_dir.display().to_string(),
+        );
 
         envs.insert(String::from("PID_FILE"), String::from(env::PID_FILE));
+        if let Some(external_url) = &self.external_url {
+            envs.insert(String::from("XUNLEI_EXTERNAL_URL"), external_url.clone());
+        }
         envs.insert(String::from("ENV_FILE"), String::from(env::ENV_FILE));
         envs.insert(String::from("LOG_FILE"), String::from(env::LOG_FILE));
         envs.insert(
@@ -132,38 +613,439 @@ impl XunleiLauncher {
 
         #[cfg(all(target_os = "linux", target_env = "musl"))]
         crate::libc_asset::ld_env(&mut envs)?;
+
+        // Applied in order, so that a value may build on an earlier one
+        for (key, value) in &self.backend_env {
+            let value = expand_env(value, &envs, self.backend_env_strict)
+                .context(format!("Failed to expand --backend-env {}", key))?;
+            envs.insert(key.clone(), value);
+        }
+        // Taken literally, to reproduce a captured environment
+        if let Some(path) = &self.env_override_file {
+            envs.extend(load_env_file(path)?);
+        }
         Ok(envs)
     }
 }
 
+// KEY=VALUE lines, blank lines and lines starting with # are skipped
+fn load_env_file(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path).context(format!(
+        "Failed to read env override file {}",
+        path.display()
+    ))?;
+    let mut entries = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        match trimmed.split_once('=') {
+            Some((key, value)) if key.is_empty().not() => {
+                entries.push((key.to_owned(), value.to_owned()))
+            }
+            _ => anyhow::bail!(
+                "Malformed env override file {} at line {}, expected KEY=VALUE",
+                path.display(),
+                index + 1
+            ),
+        }
+    }
+    Ok(entries)
+}
+
+// Expand $VAR and ${VAR} from the assembled environment, then from the launcher's own, $$ is a literal $
+fn expand_env(value: &str, envs: &HashMap<String, String>, strict: bool) -> anyhow::Result<String> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, len) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated ${{ in `{}`", value))?;
+            (&braced[..end], end + 2)
+        } else {
+            let end = rest.find(|c| is_name(c).not()).unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            continue;
+        }
+        match envs.get(name).cloned().or_else(|| std::env::var(name).ok()) {
+            Some(found) => expanded.push_str(&found),
+            None if strict => anyhow::bail!("`{}` references undefined ${}", value, name),
+            None => log::debug!("[XunleiLauncher] ${} is undefined, expanded empty", name),
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
 impl Running for XunleiLauncher {
     fn run(self) -> anyhow::Result<()> {
         use std::thread::{Builder, JoinHandle};
 
-        let args = self.clone();
-        let backend_thread: JoinHandle<_> = Builder::new()
-            .name("backend".to_string())
-            .spawn(move || match XunleiBackendServer::from(args).run() {
-                Ok(_) => {}
-                Err(e) => log::error!("[XunleiBackendServer] error: {}", e),
-            })
-            .expect("[XunleiLauncher] Failed to start backend thread");
+        // Resolve the environment up front, so that a bad --backend-env fails the launcher
+        self.envs().context(ErrorKind::Config)?;
 
-        let args = self;
-        std::thread::spawn(move || match XunleiPanelServer::from(args).run() {
-            Ok(_) => {}
-            Err(e) => log::error!("[XunleiPanelServer] error: {}", e),
-        });
+        // Spawning in a missing directory would only fail once the first process starts
+        for (used, name, dir) in [
+            (
+                self.backend_only.not(),
+                "--cgi-working-dir",
+                &self.cgi_working_dir,
+            ),
+            (
+                self.panel_only.not(),
+                "--backend-working-dir",
+                &self.backend_working_dir,
+            ),
+        ] {
+            if used && dir.is_dir().not() {
+                return Err(anyhow::anyhow!(
+                    "[XunleiLauncher] {} {} isn't a directory",
+                    name,
+                    dir.display()
+                )
+                .context(ErrorKind::Config));
+            }
+        }
+
+        // Re-extracting only restores the assets embedded in the binary, it never downloads them
+        if cfg!(feature = "embed").not()
+            && self.panel_only.not()
+            && self.on_backend_failure == OnBackendFailure::Reextract
+        {
+            return Err(anyhow::anyhow!(
+                "[XunleiLauncher] --on-backend-failure reextract needs a build with the embed feature"
+            )
+            .context(ErrorKind::Config));
+        }
+
+        // Only an explicit --uid 0/--gid 0 or --allow-root runs the backend and CGI as root
+        if self.root_by_default {
+            match self.allow_root {
+                true => log::warn!(
+                    "[XunleiLauncher] The xunlei processes run as root ({}:{}), pass --uid/--gid to drop the privileges",
+                    self.uid,
+                    self.gid
+                ),
+                false => {
+                    return Err(anyhow::anyhow!(
+                        "[XunleiLauncher] The xunlei processes would run as root ({}:{}) by default, pass --uid/--gid of an unprivileged user, or --allow-root to keep root",
+                        self.uid,
+                        self.gid
+                    )
+                    .context(ErrorKind::Config));
+                }
+            }
+        }
+
+        // Fail up front instead of with a bare EPERM from the mount or the first spawn
+        match self.test_mode {
+            true => log::warn!("[TestMode] Mounts are stubbed and the backend is a placeholder"),
+            false => util::check_capabilities(&self.required_capabilities())
+                .context(ErrorKind::Permission)?,
+        }
+
+        // The backend keeps its state in the config directory, which the backend creates when missing
+        if self.panel_only.not() && self.config_path.is_dir() {
+            util::probe_writable(&self.config_path).context(ErrorKind::Permission)?;
+        }
+
+        // Bind the panel up front, so that a busy port fails the launcher immediately
+        let panel = match self.backend_only {
+            true => {
+                log::info!("[XunleiLauncher] Backend only, the panel is not started");
+                None
+            }
+            false => Some(XunleiPanelServer::from(self.clone()).serve()?),
+        };
+
+        if let Some(panel) = panel {
+            std::thread::spawn(move || panel.run());
+        }
+        // The workers bind the port next to this panel, which already holds it
+        let workers = match self.workers > 1 {
+            true => self.spawn_workers()?,
+            false => Vec::new(),
+        };
+        if let Some(interval) = self.heartbeat_interval {
+            let stats = self.stats.clone();
+            std::thread::spawn(move || stats.heartbeat(interval));
+        }
+
+        if let Some(rotation) = self
+            .backend_log_rotation
+            .clone()
+            .filter(|_| self.panel_only.not())
+        {
+            std::thread::spawn(move || rotation.run());
+        }
+
+        let drain = self.drain.clone();
+        let shutdown_timeout = self.shutdown_timeout;
+        if self.panel_only {
+            log::info!("[XunleiLauncher] Panel only, the backend is not started");
+            wait_termination()?;
+        } else {
+            let args = self;
+            let backend_thread: JoinHandle<_> = Builder::new()
+                .name("backend".to_string())
+                .spawn(move || XunleiBackendServer::from(args).run())
+                .expect("[XunleiLauncher] Failed to start backend thread");
 
-        backend_thread
-            .join()
-            .expect("[XunleiLauncher] Failed to join thread");
+            backend_thread
+                .join()
+                .expect("[XunleiLauncher] Failed to join thread")
+                .context("[XunleiBackendServer] error")?;
+        }
+        drain.wait(shutdown_timeout);
+        stop_workers(workers);
 
         log::info!("[XunleiLauncher] All services have been complete");
         Ok(())
     }
 }
 
+impl XunleiLauncher {
+    // Start the other --workers panel processes with the same arguments
+    fn spawn_workers(&self) -> anyhow::Result<Vec<std::process::Child>> {
+        let exe =
+            std::env::current_exe().context("[XunleiLauncher] Failed to locate the launcher")?;
+        let mut workers = Vec::new();
+        for index in 1..self.workers {
+            let mut cmd = std::process::Command::new(&exe);
+            cmd.args(std::env::args_os().skip(1))
+                .env(PANEL_WORKER_ENV, index.to_string())
+                .stdin(Stdio::null());
+            // Don't outlive this process if it dies without stopping the workers
+            unsafe {
+                cmd.pre_exec(|| {
+                    match nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, nix::libc::SIGTERM) {
+                        0 => Ok(()),
+                        _ => Err(io::Error::last_os_error()),
+                    }
+                });
+            }
+            let child = cmd.spawn().context(format!(
+                "[XunleiLauncher] Failed to start panel worker {}",
+                index
+            ))?;
+            log::info!(
+                "[XunleiLauncher] Panel worker {} started, PID {}",
+                index,
+                child.id()
+            );
+            workers.push(child);
+        }
+        Ok(workers)
+    }
+
+    // The capabilities needed by the enabled services, with hints to do without them
+    fn required_capabilities(&self) -> Vec<(util::Capability, String, &'static str)> {
+        let mut required = Vec::new();
+        if self.panel_only.not() {
+            required.push((
+                util::Capability::SysAdmin,
+                format!("bind mount {}", self.mount_bind_download_path.display()),
+                "or run the backend elsewhere with --panel-only",
+            ));
+        }
+        // The backend and CGI processes are spawned as --uid/--gid
+        if self.uid != nix::unistd::geteuid().as_raw() {
+            required.push((
+                util::Capability::Setuid,
+                format!("run the xunlei processes as UID {}", self.uid),
+                "or pass --uid of the current user",
+            ));
+        }
+        if self.gid != nix::unistd::getegid().as_raw() {
+            required.push((
+                util::Capability::Setgid,
+                format!("run the xunlei processes as GID {}", self.gid),
+                "or pass --gid of the current user",
+            ));
+        }
+        if self.panel_only.not()
+            && (self.uid != nix::unistd::geteuid().as_raw()
+                || self.gid != nix::unistd::getegid().as_raw())
+        {
+            required.push((
+                util::Capability::Chown,
+                format!("chown {} to {}:{}", env::SYNOPKG_VAR, self.uid, self.gid),
+                "or pass --uid/--gid of the current user",
+            ));
+        }
+        required
+    }
+}
+
+// Stop the panel workers, killing the ones still running after WORKER_STOP_TIMEOUT
+fn stop_workers(mut workers: Vec<std::process::Child>) {
+    for worker in &workers {
+        let _ = nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(worker.id() as i32),
+            nix::sys::signal::SIGTERM,
+        );
+    }
+    let deadline = Instant::now() + WORKER_STOP_TIMEOUT;
+    for worker in workers.iter_mut() {
+        while matches!(worker.try_wait(), Ok(None)) && Instant::now() < deadline {
+            std::thread::sleep(BACKEND_POLL_INTERVAL);
+        }
+        if matches!(worker.try_wait(), Ok(None)) {
+            log::warn!("[XunleiLauncher] Kill panel worker {}", worker.id());
+            let _ = worker.kill();
+        }
+        let _ = worker.wait();
+    }
+}
+
+// Block until a termination signal arrives
+fn wait_termination() -> anyhow::Result<()> {
+    let mut signals = Signals::new([
+        signal_hook::consts::SIGINT,
+        signal_hook::consts::SIGHUP,
+        signal_hook::consts::SIGTERM,
+    ])?;
+    signals.forever().next();
+    Ok(())
+}
+
+// Counters of the panel and the backend, reported by the heartbeat
+struct Stats {
+    started: Instant,
+    requests: AtomicU64,
+    sessions: AtomicUsize,
+    // 0 while the backend isn't running
+    backend_pid: AtomicU32,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            sessions: AtomicUsize::new(0),
+            backend_pid: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Stats {
+    fn heartbeat(&self, interval: Duration) {
+        loop {
+            std::thread::sleep(interval);
+            let backend_pid = match self.backend_pid.load(Ordering::Relaxed) {
+                0 => String::from("none"),
+                pid => pid.to_string(),
+            };
+            log::info!(
+                "[XunleiLauncher] Heartbeat: uptime {}s, {} sessions, backend PID {}, {} requests in the last {}s",
+                self.started.elapsed().as_secs(),
+                self.sessions.load(Ordering::Relaxed),
+                backend_pid,
+                self.requests.swap(0, Ordering::Relaxed),
+                interval.as_secs()
+            );
+        }
+    }
+}
+
+// Counts in-flight panel requests, so that shutdown can let them finish
+#[derive(Default)]
+struct Drain {
+    draining: AtomicBool,
+    inflight: Mutex<usize>,
+    idle: Condvar,
+}
+
+// Held for the duration of a request, until its response body is sent
+struct DrainGuard(Arc<Drain>);
+
+impl DrainGuard {
+    // Move the guard into the response body, which is dropped once written out
+    fn attach(self, mut response: Response) -> Response {
+        let (reader, size) = response.data.into_reader_and_size();
+        let reader = DrainReader {
+            inner: reader,
+            _guard: self,
+        };
+        response.data = match size {
+            Some(size) => rouille::ResponseBody::from_reader_and_size(reader, size),
+            None => rouille::ResponseBody::from_reader(reader),
+        };
+        response
+    }
+}
+
+struct DrainReader<R> {
+    inner: R,
+    _guard: DrainGuard,
+}
+
+impl<R: Read> Read for DrainReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Drain {
+    // None once shutdown started, new requests are refused from then on
+    fn enter(self: &Arc<Self>) -> Option<DrainGuard> {
+        let mut inflight = self.inflight.lock().unwrap();
+        if self.draining.load(Ordering::SeqCst) {
+            return None;
+        }
+        *inflight += 1;
+        Some(DrainGuard(self.clone()))
+    }
+
+    fn wait(&self, timeout: Duration) {
+        let inflight = self.inflight.lock().unwrap();
+        self.draining.store(true, Ordering::SeqCst);
+        if *inflight == 0 {
+            return;
+        }
+        log::info!(
+            "[XunleiPanelServer] Wait up to {}s for {} in-flight requests",
+            timeout.as_secs(),
+            *inflight
+        );
+        let (inflight, result) = self
+            .idle
+            .wait_timeout_while(inflight, timeout, |inflight| *inflight > 0)
+            .unwrap();
+        if result.timed_out() {
+            log::warn!(
+                "[XunleiPanelServer] Shutdown with {} requests still in flight",
+                *inflight
+            );
+        }
+    }
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        let mut inflight = self.0.inflight.lock().unwrap();
+        *inflight -= 1;
+        if *inflight == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
 struct XunleiBackendServer {
     download_path: PathBuf,
     mount_bind_download_path: PathBuf,
@@ -171,11 +1053,46 @@ struct XunleiBackendServer {
     debug: bool,
     uid: u32,
     gid: u32,
+    cgroup_memory_max: Option<String>,
+    cgroup_cpu_max: Option<String>,
+    nice: Option<i32>,
+    ioprio: Option<(IoprioClass, u8)>,
+    cpu_affinity: Option<Vec<usize>>,
+    working_dir: PathBuf,
+    config_path: PathBuf,
+    chroot: bool,
+    test_mode: bool,
+    // The assembled root while the backend runs chrooted
+    chroot_root: Option<PathBuf>,
+    // The CGI bridge of the --post-start requests
+    post_start: Option<(Box<XunleiPanelServer>, Vec<String>)>,
+    post_start_strict: bool,
+    on_ready: Option<String>,
+    ready_timeout: Option<Duration>,
+    ready_interval: Duration,
+    drain_on_shutdown: Option<String>,
+    drain_timeout: Duration,
+    on_shutdown: Option<String>,
+    restart_max: u32,
+    restart_window: Option<Duration>,
+    on_backend_failure: OnBackendFailure,
+    umount_on_exit: bool,
+    mount_wait: Duration,
+    mount_propagation: MountPropagation,
+    restart_requested: Arc<AtomicBool>,
+    stats: Arc<Stats>,
 }
 
 impl From<XunleiLauncher> for XunleiBackendServer {
     fn from(launcher: XunleiLauncher) -> Self {
         let envs = launcher.envs().unwrap();
+        let post_start = match launcher.post_start.is_empty() {
+            true => None,
+            false => Some((
+                Box::new(XunleiPanelServer::from(launcher.clone())),
+                launcher.post_start.clone(),
+            )),
+        };
         Self {
             download_path: launcher.download_path,
             mount_bind_download_path: launcher.mount_bind_download_path,
@@ -183,122 +1100,728 @@ impl From<XunleiLauncher> for XunleiBackendServer {
             debug: launcher.debug,
             uid: launcher.uid,
             gid: launcher.gid,
+            cgroup_memory_max: launcher.cgroup_memory_max,
+            cgroup_cpu_max: launcher.cgroup_cpu_max,
+            nice: launcher.backend_nice,
+            ioprio: launcher.backend_ioprio,
+            cpu_affinity: launcher.backend_cpu_affinity,
+            working_dir: launcher.backend_working_dir,
+            config_path: launcher.config_path,
+            chroot: launcher.chroot,
+            test_mode: launcher.test_mode,
+            chroot_root: None,
+            post_start,
+            post_start_strict: launcher.post_start_strict,
+            on_ready: launcher.on_backend_ready,
+            ready_timeout: launcher.ready_timeout,
+            ready_interval: launcher.ready_interval,
+            drain_on_shutdown: launcher.drain_on_shutdown,
+            drain_timeout: launcher.drain_timeout,
+            on_shutdown: launcher.on_shutdown,
+            restart_max: launcher.backend_restart_max,
+            restart_window: launcher.backend_restart_window,
+            on_backend_failure: launcher.on_backend_failure,
+            umount_on_exit: launcher.umount_on_exit,
+            mount_wait: launcher.mount_wait,
+            mount_propagation: launcher.mount_propagation,
+            restart_requested: launcher.restart_backend,
+            stats: launcher.stats,
         }
     }
 }
 
-impl Running for XunleiBackendServer {
-    fn run(self) -> anyhow::Result<()> {
-        let var_path = Path::new(env::SYNOPKG_VAR);
-        if var_path.exists().not() {
-            util::create_dir_all(var_path, 0o777)?;
-            util::chown(var_path, self.uid, self.gid)?;
-        }
-
-        let _ = nix::mount::umount(&self.mount_bind_download_path);
-        match nix::mount::mount(
-            Some(&self.download_path),
-            &self.mount_bind_download_path,
-            <Option<&'static [u8]>>::None,
-            MsFlags::MS_BIND,
-            <Option<&'static [u8]>>::None,
-        ) {
-            Ok(_) => {
-                log::info!(
-                    "[XunleiBackendServer] Mount {} to {} succeeded",
-                    self.download_path.display(),
-                    self.mount_bind_download_path.display()
-                )
-            }
-            Err(_) => {
-                anyhow::bail!(
-                    "[XunleiBackendServer] Mount {} to {} failed",
-                    self.download_path.display(),
-                    self.mount_bind_download_path.display()
-                );
-            }
-        };
-
+impl XunleiBackendServer {
+    fn spawn(&self, cgroup: Option<&Cgroup>) -> anyhow::Result<std::process::Child> {
         log::info!("[XunleiBackendServer] Start Xunlei Backend Server");
-        let mut cmd = std::process::Command::new(env::LAUNCHER_EXE);
-        cmd.args([
+        let args = [
             format!("-launcher_listen={}", env::LAUNCHER_SOCK),
             format!("-pid={}", env::PID_FILE),
             format!("-logfile={}", env::LAUNCH_LOG_FILE),
-        ])
-        .current_dir(env::SYNOPKG_PKGDEST)
-        .uid(self.uid)
-        .gid(self.gid)
-        .envs(self.envs);
+        ];
+        let mut cmd = match self.test_mode {
+            true => {
+                log::info!(
+                    "[TestMode] Run a placeholder instead of {} {} as {}:{} in {}",
+                    env::LAUNCHER_EXE,
+                    args.join(" "),
+                    self.uid,
+                    self.gid,
+                    self.working_dir.display()
+                );
+                let mut cmd = std::process::Command::new("/bin/sh");
+                cmd.args([
+                    "-c",
+                    TEST_MODE_BACKEND,
+                    "backend",
+                    env::SOCK_FILE.trim_start_matches("unix://"),
+                ]);
+                cmd
+            }
+            false => {
+                let mut cmd = std::process::Command::new(env::LAUNCHER_EXE);
+                cmd.args(&args);
+                cmd
+            }
+        };
+        cmd.current_dir(&self.working_dir).envs(&self.envs);
+        let procs = cgroup.map(Cgroup::procs);
+        let root = self.chroot_root.clone();
+        // Changing the user drops the privileges to join the cgroup and to chroot, so it
+        // waits until after them
+        let user = match self.test_mode {
+            true => None,
+            false => Some((
+                nix::unistd::Uid::from_raw(self.uid),
+                nix::unistd::Gid::from_raw(self.gid),
+            )),
+        };
+        if procs.is_none() && root.is_none() {
+            if let Some((uid, gid)) = user {
+                cmd.uid(uid.as_raw()).gid(gid.as_raw());
+            }
+        }
         if !self.debug {
             cmd.stderr(Stdio::null())
                 .stdin(Stdio::null())
                 .stdout(Stdio::null());
         }
-        let backend_process = cmd.spawn()?;
-        let backend_pid = backend_process.id() as i32;
+
+        if procs.is_some() || root.is_some() {
+            let working_dir = self.working_dir.clone();
+            unsafe {
+                cmd.pre_exec(move || {
+                    // Join the cgroup before exec, so that everything the backend forks is limited
+                    // too, a failure is reported by the parent
+                    if let Some(procs) = &procs {
+                        let _ = Cgroup::attach(procs);
+                    }
+                    if let Some(root) = &root {
+                        nix::unistd::chroot(root)?;
+                        nix::unistd::chdir(&working_dir)?;
+                    }
+                    if let Some((uid, gid)) = user {
+                        if nix::unistd::getuid().is_root() {
+                            nix::unistd::setgroups(&[])?;
+                        }
+                        nix::unistd::setgid(gid)?;
+                        nix::unistd::setuid(uid)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        let backend_process = cmd.spawn().map_err(|e| {
+            let kind = match e.kind() {
+                io::ErrorKind::NotFound => ErrorKind::Asset,
+                io::ErrorKind::PermissionDenied => ErrorKind::Permission,
+                _ => return anyhow::anyhow!(e),
+            };
+            anyhow::anyhow!(e).context(kind)
+        })?;
+        let backend_pid = backend_process.id();
         log::info!(
             "[XunleiBackendServer] Xunlei Backend Server PID: {}",
             backend_pid
         );
+        if let Some(cgroup) = cgroup {
+            if cgroup.contains(backend_pid).not() {
+                log::warn!("[XunleiBackendServer] Failed to move the backend into the cgroup");
+            }
+        }
+        self.prioritize(backend_pid);
+        self.stats.backend_pid.store(backend_pid, Ordering::Relaxed);
+        Ok(backend_process)
+    }
 
-        let mut signals = Signals::new([
-            signal_hook::consts::SIGINT,
-            signal_hook::consts::SIGHUP,
-            signal_hook::consts::SIGTERM,
-        ])?;
-
-        for signal in signals.forever() {
-            match signal {
-                signal_hook::consts::SIGINT
-                | signal_hook::consts::SIGHUP
-                | signal_hook::consts::SIGTERM => {
-                    match nix::sys::signal::kill(
-                        Pid::from_raw(backend_pid),
-                        nix::sys::signal::SIGINT,
-                    ) {
-                        Ok(_) => {
-                            log::info!(
-                                "[XunleiBackendServer] The backend service has been terminated"
-                            )
-                        }
-                        Err(_) => {
-                            nix::sys::signal::kill(Pid::from_raw(backend_pid),
-                            nix::sys::signal::SIGTERM).expect(&format!("[XunleiBackendServer] The backend kill error: {}, An attempt was made to send SIGTERM to continue terminating",
-                                                        std::io::Error::last_os_error()));
-                        }
-                    }
-                    break;
-                }
-                _ => {
-                    log::warn!("[XunleiBackendServer] The system receives an unprocessed signal")
-                }
+    // Apply the CPU and IO priorities and the CPU affinity to the backend, it forks its workers
+    // later so they inherit them
+    fn prioritize(&self, pid: u32) {
+        if let Some(cpus) = &self.cpu_affinity {
+            let mut set = nix::sched::CpuSet::new();
+            for cpu in cpus {
+                let _ = set.set(*cpu);
+            }
+            match nix::sched::sched_setaffinity(Pid::from_raw(pid as i32), &set) {
+                Ok(_) => log::info!("[XunleiBackendServer] Backend CPU affinity: {:?}", cpus),
+                Err(e) => log::warn!(
+                    "[XunleiBackendServer] Skip backend CPU affinity {:?}: {}",
+                    cpus,
+                    e
+                ),
+            }
+        }
+        if let Some(nice) = self.nice {
+            match unsafe { nix::libc::setpriority(nix::libc::PRIO_PROCESS as _, pid, nice) } {
+                0 => log::info!("[XunleiBackendServer] Backend nice: {}", nice),
+                _ => log::warn!(
+                    "[XunleiBackendServer] Skip backend nice {}: {}",
+                    nice,
+                    nix::errno::Errno::last()
+                ),
+            }
+        }
+        if let Some((class, level)) = self.ioprio {
+            let (class_id, level) = match class {
+                IoprioClass::Realtime => (IOPRIO_CLASS_RT, level),
+                IoprioClass::BestEffort => (IOPRIO_CLASS_BE, level),
+                IoprioClass::Idle => (IOPRIO_CLASS_IDLE, 0),
+            };
+            let ioprio = (class_id << IOPRIO_CLASS_SHIFT) | level as nix::libc::c_int;
+            match unsafe {
+                nix::libc::syscall(nix::libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, pid, ioprio)
+            } {
+                0 => log::info!(
+                    "[XunleiBackendServer] Backend IO priority: {:?} {}",
+                    class,
+                    level
+                ),
+                _ => log::warn!(
+                    "[XunleiBackendServer] Skip backend IO priority {:?}: {}",
+                    class,
+                    nix::errno::Errno::last()
+                ),
             }
         }
+    }
 
-        // umount bind directory
-        match nix::mount::umount(&self.mount_bind_download_path) {
+    fn terminate(backend: &std::process::Child) {
+        let backend_pid = backend.id() as i32;
+        match nix::sys::signal::kill(Pid::from_raw(backend_pid), nix::sys::signal::SIGINT) {
             Ok(_) => {
-                log::info!(
-                    "[XunleiBackendServer] Unmount {} succeeded",
-                    self.mount_bind_download_path.display()
-                )
+                log::info!("[XunleiBackendServer] The backend service has been terminated")
             }
             Err(_) => {
-                log::error!(
-                    "[XunleiBackendServer] Unmount {} failed",
-                    self.mount_bind_download_path.display()
-                )
+                nix::sys::signal::kill(Pid::from_raw(backend_pid), nix::sys::signal::SIGTERM)
+                    .unwrap_or_else(|_| {
+                        panic!(
+                            "[XunleiBackendServer] The backend kill error: {}, An attempt was made to send SIGTERM to continue terminating",
+                            std::io::Error::last_os_error()
+                        )
+                    });
+            }
+        }
+    }
+
+    // Terminate the backend and wait for it to exit, killing it when it takes too long
+    fn stop(backend: &mut std::process::Child) {
+        Self::terminate(backend);
+        let deadline = Instant::now() + BACKEND_STOP_TIMEOUT;
+        while let Ok(None) = backend.try_wait() {
+            if Instant::now() >= deadline {
+                log::warn!(
+                    "[XunleiBackendServer] The backend service didn't exit in time, kill it"
+                );
+                let _ = backend.kill();
+                let _ = backend.wait();
+                return;
+            }
+            std::thread::sleep(BACKEND_POLL_INTERVAL);
+        }
+    }
+
+    // Run a hook through the shell as the backend user, with the backend environment
+    fn run_hook(&self, name: &'static str, command: &str) -> Option<std::thread::JoinHandle<()>> {
+        log::info!("[XunleiBackendServer] Run the {} hook: {}", name, command);
+        let child = std::process::Command::new("/bin/sh")
+            .args(["-c", command])
+            .current_dir(&self.working_dir)
+            .envs(&self.envs)
+            .uid(self.uid)
+            .gid(self.gid)
+            .stdin(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                log::error!(
+                    "[XunleiBackendServer] Failed to run the {} hook: {}",
+                    name,
+                    e
+                );
+                return None;
             }
         };
+        // Wait from a thread, so that a slow hook doesn't hold up the supervisor
+        Some(std::thread::spawn(move || match child.wait() {
+            Ok(status) if status.success() => {
+                log::info!("[XunleiBackendServer] The {} hook exited: {}", name, status)
+            }
+            Ok(status) => log::warn!("[XunleiBackendServer] The {} hook exited: {}", name, status),
+            Err(e) => log::warn!(
+                "[XunleiBackendServer] Failed to wait for the {} hook: {}",
+                name,
+                e
+            ),
+        }))
+    }
+
+    // The experimental chroot is best effort, the backend runs unconfined when it can't be set up
+    fn assemble_chroot(&self) -> Option<Chroot> {
+        if self.test_mode {
+            log::info!("[TestMode] Skip the chroot");
+            return None;
+        }
+        let missing = [util::Capability::SysChroot, util::Capability::SysAdmin]
+            .into_iter()
+            .filter(|capability| util::has_capability(*capability).not())
+            .map(util::Capability::name)
+            .collect::<Vec<_>>();
+        if missing.is_empty().not() {
+            log::warn!(
+                "[XunleiBackendServer] Skip the chroot, missing {}",
+                missing.join(", ")
+            );
+            return None;
+        }
+        let mut paths = vec![
+            PathBuf::from(env::SYNOPKG_PKGDEST),
+            self.working_dir.clone(),
+            self.config_path.clone(),
+            self.mount_bind_download_path.clone(),
+        ];
+        paths.extend(env::CHROOT_HOST_PATHS.iter().map(PathBuf::from));
+        match Chroot::assemble(env::SYNOPKG_CHROOT, &paths) {
+            Ok(chroot) => {
+                log::warn!(
+                    "[XunleiBackendServer] Run the backend chrooted into {} (experimental)",
+                    chroot.root().display()
+                );
+                Some(chroot)
+            }
+            Err(e) => {
+                log::warn!("[XunleiBackendServer] Skip the chroot: {}", e);
+                None
+            }
+        }
+    }
 
+    // The --post-start requests of a backend that just came up, stopping at the first failure
+    fn run_post_start(&self) -> anyhow::Result<()> {
+        let Some((panel, paths)) = &self.post_start else {
+            return Ok(());
+        };
+        for path in paths {
+            let status_code = panel.request_cgi(path)?;
+            if (200..400).contains(&status_code).not() {
+                anyhow::bail!(
+                    "[XunleiBackendServer] The post start request {} answered {}",
+                    path,
+                    status_code
+                )
+            }
+            log::info!(
+                "[XunleiBackendServer] The post start request {} answered {}",
+                path,
+                status_code
+            );
+        }
         Ok(())
     }
+
+    // Ask the backend to pause its downloads, so that signaling it right after doesn't cut them off
+    fn drain(&self, path: &str) {
+        log::info!(
+            "[XunleiBackendServer] Drain the backend downloads: POST {}",
+            path
+        );
+        let started = Instant::now();
+        match Self::post_backend(path, started + self.drain_timeout) {
+            Ok(code) if (200..300).contains(&code) => log::info!(
+                "[XunleiBackendServer] The backend drained in {:.1}s",
+                started.elapsed().as_secs_f64()
+            ),
+            Ok(code) => log::warn!(
+                "[XunleiBackendServer] The backend doesn't support draining, {} answered {}, stop it right away",
+                path,
+                code
+            ),
+            Err(e) => log::warn!(
+                "[XunleiBackendServer] Failed to drain the backend: {}, stop it right away",
+                e
+            ),
+        }
+    }
+
+    // POST to the backend socket and return the status code, the backend answers once it is done
+    fn post_backend(path: &str, deadline: Instant) -> anyhow::Result<u16> {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        let mut stream = UnixStream::connect(env::SOCK_FILE.trim_start_matches("unix://"))?;
+        stream.set_write_timeout(Some(deadline.saturating_duration_since(Instant::now())))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            path
+        )?;
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                anyhow::bail!("no answer in time")
+            }
+            stream.set_read_timeout(Some(remaining))?;
+            let read = stream.read(&mut buf)?;
+            if read == 0 {
+                anyhow::bail!("the connection closed before an answer")
+            }
+            head.extend_from_slice(&buf[..read]);
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut response = httparse::Response::new(&mut headers);
+            if response.parse(&head)?.is_complete() {
+                return response.code.context("the answer has no status code");
+            }
+        }
+    }
+
+    // Stale pid and socket files of a dead backend would get in the way of its restart
+    fn clean_runtime_files() {
+        for path in [
+            env::PID_FILE,
+            env::LAUNCH_PID_FILE,
+            env::SOCK_FILE.trim_start_matches("unix://"),
+            env::LAUNCHER_SOCK.trim_start_matches("unix://"),
+        ] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    // Run the backend until a termination signal arrives, restarting it when it exits
+    fn supervise(&self, cgroup: Option<&Cgroup>) -> anyhow::Result<()> {
+        let mut signals = Signals::new([
+            signal_hook::consts::SIGINT,
+            signal_hook::consts::SIGHUP,
+            signal_hook::consts::SIGTERM,
+            signal_hook::consts::SIGCHLD,
+        ])?;
+        let handle = signals.handle();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if tx.send(signal).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let result = self.supervise_backend(cgroup, &rx);
+        handle.close();
+        result
+    }
+
+    fn supervise_backend(
+        &self,
+        cgroup: Option<&Cgroup>,
+        signals: &mpsc::Receiver<i32>,
+    ) -> anyhow::Result<()> {
+        Self::clean_runtime_files();
+        let mut backend = self.spawn(cgroup)?;
+        // Times of the restarts that still count towards the limit
+        let mut restarts = VecDeque::new();
+        let mut reextracted = false;
+        // The backend PID waited on, since when and whether its socket is up
+        let mut readiness = (backend.id(), Instant::now(), false);
+        loop {
+            if readiness.0 != backend.id() {
+                readiness = (backend.id(), Instant::now(), false);
+            }
+            // Sleep until the next poll unless a signal comes first, so that SIGCHLD gets a
+            // crashed backend noticed right away
+            let poll = match readiness.2 {
+                true => BACKEND_POLL_INTERVAL,
+                false => self.ready_interval,
+            };
+            let first = signals.recv_timeout(poll).ok();
+            for signal in first.into_iter().chain(signals.try_iter()) {
+                match signal {
+                    signal_hook::consts::SIGINT
+                    | signal_hook::consts::SIGHUP
+                    | signal_hook::consts::SIGTERM => {
+                        if let Some(path) = &self.drain_on_shutdown {
+                            self.drain(path);
+                        }
+                        // The shutdown hook and the unmounts wait for the backend to be gone
+                        Self::stop(&mut backend);
+                        return Ok(());
+                    }
+                    // The panel CGI processes raise it too, try_wait below only reaps the backend
+                    signal_hook::consts::SIGCHLD => {}
+                    _ => {
+                        log::warn!(
+                            "[XunleiBackendServer] The system receives an unprocessed signal"
+                        )
+                    }
+                }
+            }
+
+            if self.restart_requested.swap(false, Ordering::SeqCst) {
+                log::info!("[XunleiBackendServer] Restart the backend service on request");
+                Self::stop(&mut backend);
+                Self::clean_runtime_files();
+                backend = self.spawn(cgroup)?;
+                continue;
+            }
+
+            // Every start removes the socket first, so its appearance means this backend is ready
+            let mut failed = false;
+            if readiness.2.not() {
+                let waited = readiness.1.elapsed();
+                if Path::new(env::SOCK_FILE.trim_start_matches("unix://")).exists() {
+                    readiness.2 = true;
+                    if let Err(e) = self.run_post_start() {
+                        if self.post_start_strict {
+                            Self::stop(&mut backend);
+                            return Err(e.context(ErrorKind::Backend));
+                        }
+                        log::error!("{}", e);
+                    }
+                    log::info!(
+                        "[XunleiBackendServer] The backend service is ready after {:.1}s",
+                        waited.as_secs_f64()
+                    );
+                    if let Some(command) = &self.on_ready {
+                        self.run_hook("backend ready", command);
+                    }
+                } else if self.ready_timeout.is_some_and(|timeout| waited >= timeout) {
+                    log::error!(
+                        "[XunleiBackendServer] The backend service socket didn't come up after {:.1}s",
+                        waited.as_secs_f64()
+                    );
+                    Self::stop(&mut backend);
+                    failed = true;
+                }
+            }
+
+            if failed {
+                self.stats.backend_pid.store(0, Ordering::Relaxed);
+            } else if let Some(status) = backend.try_wait()? {
+                self.stats.backend_pid.store(0, Ordering::Relaxed);
+                log::error!(
+                    "[XunleiBackendServer] The backend service exited: {}",
+                    status
+                );
+                failed = true;
+            }
+            if failed {
+                if let Some(window) = self.restart_window {
+                    while restarts
+                        .front()
+                        .is_some_and(|at: &Instant| at.elapsed() > window)
+                    {
+                        restarts.pop_front();
+                    }
+                }
+                if restarts.len() < self.restart_max as usize {
+                    restarts.push_back(Instant::now());
+                    log::warn!(
+                        "[XunleiBackendServer] Restart the backend service ({}/{})",
+                        restarts.len(),
+                        self.restart_max
+                    );
+                    std::thread::sleep(BACKEND_RESTART_DELAY);
+                    Self::clean_runtime_files();
+                    backend = self.spawn(cgroup)?;
+                    continue;
+                }
+                match self.on_backend_failure {
+                    OnBackendFailure::Reextract if reextracted.not() => {
+                        reextracted = true;
+                        log::warn!("[XunleiBackendServer] Re-extract the assets and retry once");
+                        xunlei_asset::reextract(
+                            Path::new(env::SYNOPKG_PKGDEST),
+                            self.uid,
+                            self.gid,
+                        )
+                        .context(ErrorKind::Asset)?;
+                        Self::clean_runtime_files();
+                        backend = self.spawn(cgroup)?;
+                    }
+                    OnBackendFailure::Hold => {
+                        log::error!("[XunleiBackendServer] The backend service failed to stay up, holding until shutdown");
+                        for signal in signals.iter() {
+                            if matches!(
+                                signal,
+                                signal_hook::consts::SIGINT
+                                    | signal_hook::consts::SIGHUP
+                                    | signal_hook::consts::SIGTERM
+                            ) {
+                                break;
+                            }
+                        }
+                        return Ok(());
+                    }
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "[XunleiBackendServer] The backend service failed to stay up after {} restarts",
+                            restarts.len()
+                        )
+                        .context(ErrorKind::Backend));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Running for XunleiBackendServer {
+    fn run(mut self) -> anyhow::Result<()> {
+        let var_path = Path::new(env::SYNOPKG_VAR);
+        if var_path.exists().not() {
+            util::create_dir_all(var_path, 0o777)?;
+            util::chown(var_path, self.uid, self.gid)?;
+        }
+
+        let bind_mount = BindMount::with_mounter(
+            &self.download_path,
+            &self.mount_bind_download_path,
+            mount::mounter(self.test_mode),
+        );
+        bind_mount.wait_source(self.mount_wait)?;
+        bind_mount.mount()?;
+        bind_mount.set_propagation(self.mount_propagation)?;
+        if self.umount_on_exit {
+            // A panic of the launcher or the supervisor leaves the mounts in an unknown state, so
+            // unmount and abort. The panel handlers unwind as before, their panics become a 500
+            let source = self.download_path.clone();
+            let target = self.mount_bind_download_path.clone();
+            let test_mode = self.test_mode;
+            let supervisor = std::thread::current().id();
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                previous(info);
+                let thread = std::thread::current();
+                if thread.id() != supervisor && thread.name() != Some("main") {
+                    return;
+                }
+                log::error!("[XunleiBackendServer] Unmount the bind directory after a panic");
+                let bind_mount =
+                    BindMount::with_mounter(&source, &target, mount::mounter(test_mode));
+                if let Err(e) = bind_mount.umount() {
+                    log::error!("{}", e)
+                }
+                std::process::abort();
+            }));
+        }
+
+        let mut chroot = match self.chroot {
+            true => self.assemble_chroot(),
+            false => None,
+        };
+        self.chroot_root = chroot.as_ref().map(|chroot| chroot.root().to_path_buf());
+
+        let cgroup = match self.cgroup_memory_max.is_some() || self.cgroup_cpu_max.is_some() {
+            true => Cgroup::create(
+                env::APP_NAME,
+                self.cgroup_memory_max.as_deref(),
+                self.cgroup_cpu_max.as_deref(),
+            ),
+            false => None,
+        };
+
+        let result = self.supervise(cgroup.as_ref());
+        if let Some(command) = &self.on_shutdown {
+            if let Some(hook) = self.run_hook("shutdown", command) {
+                let _ = hook.join();
+            }
+        }
+        if let Some(chroot) = chroot.as_mut() {
+            chroot.umount();
+        }
+
+        if self.umount_on_exit.not() {
+            log::info!(
+                "[XunleiBackendServer] Leave {} mounted on exit",
+                self.mount_bind_download_path.display()
+            );
+            return result;
+        }
+
+        // umount bind directory
+        log::info!("[XunleiBackendServer] Unmount the bind directory on exit");
+        if let Err(e) = bind_mount.umount() {
+            log::error!("{}", e)
+        }
+
+        result
+    }
+}
+
+// Panel bytes of one user. Requests count the body bytes the routes actually read
+#[derive(Debug, Clone, Default, serde::Serialize)]
+struct Traffic {
+    received: u64,
+    sent: u64,
+}
+
+thread_local! {
+    // Request body bytes read for the request handled on this thread, shared with the
+    // threads feeding the body to a CGI
+    static RECEIVED: std::cell::RefCell<Arc<AtomicU64>> = Default::default();
+}
+
+impl Traffic {
+    fn receive(bytes: u64) {
+        RECEIVED.with(|received| received.borrow().fetch_add(bytes, Ordering::Relaxed));
+    }
+
+    fn received() -> Arc<AtomicU64> {
+        RECEIVED.with(|received| received.borrow().clone())
+    }
+
+    // Start counting a new request, late reads of the previous body still add to its counter
+    fn take_received() -> Arc<AtomicU64> {
+        RECEIVED.with(|received| received.take())
+    }
+
+    fn account(
+        traffic: &Arc<Mutex<HashMap<String, Traffic>>>,
+        user: String,
+        mut response: Response,
+    ) -> Response {
+        let (reader, size) = response.data.into_reader_and_size();
+        let reader = TrafficReader {
+            inner: reader,
+            received: Self::take_received(),
+            sent: 0,
+            user,
+            traffic: traffic.clone(),
+        };
+        response.data = match size {
+            Some(size) => rouille::ResponseBody::from_reader_and_size(reader, size),
+            None => rouille::ResponseBody::from_reader(reader),
+        };
+        response
+    }
+}
+
+// Counts the response body as it is written out, and adds it to the user once done along
+// with the request body, which a CGI may still be fed meanwhile
+struct TrafficReader<R> {
+    inner: R,
+    received: Arc<AtomicU64>,
+    sent: u64,
+    user: String,
+    traffic: Arc<Mutex<HashMap<String, Traffic>>>,
+}
+
+impl<R: Read> Read for TrafficReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.sent += read as u64;
+        Ok(read)
+    }
 }
 
-// This struct contains the data that we store on the server about each client.
-#[derive(Debug, Clone)]
-struct Session;
+impl<R> Drop for TrafficReader<R> {
+    fn drop(&mut self) {
+        if let Ok(mut traffic) = self.traffic.lock() {
+            let traffic = traffic.entry(std::mem::take(&mut self.user)).or_default();
+            traffic.received += self.received.load(Ordering::Relaxed);
+            traffic.sent += self.sent;
+        }
+    }
+}
 
 #[macro_export]
 macro_rules! try_or_400 {
@@ -314,21 +1837,139 @@ macro_rules! try_or_400 {
 }
 
 struct XunleiPanelServer {
+    auth_user_name: Option<String>,
     auth_user: Option<String>,
     auth_password: Option<String>,
-    host: std::net::IpAddr,
+    auth_file: Option<PathBuf>,
+    max_login_body: u64,
+    max_request_body: u64,
+    login_csp_nonce: bool,
+    host: ListenHost,
     port: u16,
     envs: HashMap<String, String>,
     debug: bool,
     uid: u32,
     gid: u32,
+    request_timeout: Option<Duration>,
+    download_path: PathBuf,
+    mount_bind_download_path: PathBuf,
+    listen_options: ListenOptions,
+    #[cfg(feature = "http2")]
+    http2: bool,
+    web_ui_home: String,
+    probe_url: String,
+    base_path: String,
+    allowed_hosts: Vec<String>,
+    trusted_proxies: Vec<std::net::IpAddr>,
+    external_url: Option<String>,
+    error_page_path: Option<PathBuf>,
+    error_page: String,
+    login_page: String,
+    // The xunlei CGI program, SYNOPKG_CLI_WEB
+    cgi_program: PathBuf,
+    cgi_working_dir: PathBuf,
+    cgi_cache_policy: CgiCachePolicy,
+    cgi_spawn_retries: u32,
+    cgi_buffer_size: usize,
+    tls_cert: Vec<(Option<String>, PathBuf)>,
+    tls_key: Vec<PathBuf>,
+    min_tls_version: TlsVersion,
+    tls_cipher_policy: TlsCipherPolicy,
+    drain: Arc<Drain>,
+    stats: Arc<Stats>,
+    admin_token: Option<String>,
+    restart_backend: Arc<AtomicBool>,
+    session_prune_interval: Duration,
+    session_store: SessionStoreKind,
+    sessions: Arc<dyn SessionStore>,
+    // Bytes per user, when --session-accounting is on
+    traffic: Option<Arc<Mutex<HashMap<String, Traffic>>>>,
+    fallback: Box<dyn PanelFallback>,
+}
+
+// Answers the logged in requests the panel routes don't handle. Production bridges them
+// to the xunlei CGI program, a canned implementation lets the routing run without one
+trait PanelFallback: Send + Sync {
+    fn respond(
+        &self,
+        panel: &XunleiPanelServer,
+        request: &Request,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Response>;
+}
+
+struct CgiFallback;
+
+impl PanelFallback for CgiFallback {
+    fn respond(
+        &self,
+        panel: &XunleiPanelServer,
+        request: &Request,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Response> {
+        panel.handle_cgi(request, deadline)
+    }
+}
+
+// Answers in place of the CGI program with --test-mode
+struct TestModeFallback;
+
+impl PanelFallback for TestModeFallback {
+    fn respond(
+        &self,
+        _panel: &XunleiPanelServer,
+        request: &Request,
+        _deadline: Option<Instant>,
+    ) -> anyhow::Result<Response> {
+        log::info!(
+            "[TestMode] Answer {} {} instead of the CGI program",
+            request.method(),
+            request.raw_url()
+        );
+        Ok(Response::text(format!(
+            "test mode: {} {}",
+            request.method(),
+            request.raw_url()
+        )))
+    }
 }
 
 impl XunleiPanelServer {
-    fn authentication(&self, auth_user: String, auth_password: String) -> bool {
+    // The name of the user the hashed credentials belong to, None when they don't match
+    fn authentication(&self, auth_user: String, auth_password: String) -> Option<String> {
+        // The file is read again on every login, so that edits apply without a restart.
+        // Any failure to read it denies the login instead of falling back to the CLI credential
+        if let Some(path) = &self.auth_file {
+            return match load_auth_file(path) {
+                Ok(credentials) => credentials
+                    .into_iter()
+                    .find(|c| auth_user.eq(&c.user) && auth_password.eq(&c.password))
+                    .map(|c| c.name),
+                Err(e) => {
+                    log::error!("[XunleiPanelServer] Login denied: {:#}", e);
+                    None
+                }
+            };
+        }
         let raw_auth_user = self.auth_user.clone().unwrap_or_default();
         let raw_auth_password = self.auth_password.clone().unwrap_or_default();
-        auth_user.eq(&raw_auth_user) && auth_password.eq(&raw_auth_password)
+        (auth_user.eq(&raw_auth_user) && auth_password.eq(&raw_auth_password))
+            .then(|| self.auth_user_name.clone().unwrap_or_default())
+    }
+
+    // Whether the Host header is in --allowed-host, which guards against DNS rebinding
+    fn host_allowed(&self, request: &Request) -> bool {
+        if self.allowed_hosts.is_empty() {
+            return true;
+        }
+        let Some((host, name, _)) = forwarded_origin(request.header("Host"), None).host else {
+            return false;
+        };
+        let unbracketed = |s: &str| s.trim_start_matches('[').trim_end_matches(']').to_owned();
+        self.allowed_hosts.iter().any(|allowed| {
+            allowed.eq_ignore_ascii_case(host)
+                || unbracketed(allowed).eq_ignore_ascii_case(&unbracketed(name))
+        })
     }
 
     #[allow(unreachable_code)]
@@ -336,20 +1977,65 @@ impl XunleiPanelServer {
         &self,
         request: &Request,
         session_data: &mut Option<Session>,
+        deadline: Option<Instant>,
     ) -> anyhow::Result<Response> {
-        if self.auth_user.is_none() || self.auth_password.is_none() {
-            *session_data = Some(Session {});
+        if self.host_allowed(request).not() {
+            log::warn!(
+                "[XunleiPanelServer] Reject Host {:?} from {}",
+                request.header("Host").unwrap_or_default(),
+                request.remote_addr()
+            );
+            return Ok(Response::text("Misdirected Request").with_status_code(421));
+        }
+
+        // Route requests under the base path as if they were made at the root
+        let unprefixed = match self.base_path.is_empty() {
+            true => None,
+            false => request
+                .remove_prefix(&self.base_path)
+                .filter(|request| request.url().is_empty() || request.url().starts_with('/')),
+        };
+        let request = unprefixed.as_ref().unwrap_or(request);
+
+        // Readiness is public, so that orchestrators can probe it without a session
+        if request.method() == "GET" && request.url() == "/readyz" {
+            return self.readiness(request, deadline);
+        }
+
+        if self.auth_file.is_none() && (self.auth_user.is_none() || self.auth_password.is_none()) {
+            *session_data = Some(Session::new(None));
         }
 
         rouille::router!(request,
             (POST) (/login) => {
-                let data = try_or_400!(rouille::post_input!(request, {
+                // Read at most one byte over the cap, so that oversized bodies are never buffered whole
+                let mut body = Vec::new();
+                if let Some(data) = request_body(request) {
+                    data.take(self.max_login_body + 1).read_to_end(&mut body)?;
+                }
+                Traffic::receive(body.len() as u64);
+                if body.len() as u64 > self.max_login_body {
+                    return Ok(Response::text("Login form too large").with_status_code(413));
+                }
+                let form = Request::fake_http_from(
+                    request.remote_addr().to_owned(),
+                    request.method(),
+                    request.raw_url(),
+                    request
+                        .header("Content-Type")
+                        .map(|v| vec![("Content-Type".to_owned(), v.to_owned())])
+                        .unwrap_or_default(),
+                    body,
+                );
+                let data = try_or_400!(rouille::post_input!(&form, {
                     auth_user: String,
                     auth_password: String,
                 }));
-                if self.authentication(data.auth_user, data.auth_password) {
-                    *session_data = Some(Session{});
-                    return Ok(Response::redirect_303("/"));
+                try_or_400!(login_field(&data.auth_user, "auth_user"));
+                try_or_400!(login_field(&data.auth_password, "auth_password"));
+                if let Some(user) = self.authentication(data.auth_user, data.auth_password) {
+                    *session_data = Some(Session::new(Some(user)));
+                    return Ok(Response::redirect_303(format!("{}/", self.base_path)));
                 } else {
                     return Ok(Response::html("Wrong login/password"));
                 }
@@ -359,175 +2045,1696 @@ impl XunleiPanelServer {
 
         if let Some(_session_data) = session_data.as_ref() {
             // Logged in.
-            self.handle_route_logged_in(request)
+            self.handle_route_logged_in(request, deadline)
         } else {
             // Not logged in.
             router!(request,
                 (GET) ["/login"] => {
-                    Ok(Response::html(HTML_LOGIN))
+                    Ok(self.login_response())
                 },
                 (GET) ["/js/sha3.min.js"] => {
                     Ok(Response::html(JS_SHA3))
                 },
                 _ => {
-                    Ok(Response::redirect_303("/login"))
+                    Ok(Response::redirect_303(format!("{}/login", self.base_path)))
                 }
             )
         }
     }
 
     // This function handles the routes that are accessible only if the user is logged in.
-    fn handle_route_logged_in(&self, request: &Request) -> anyhow::Result<Response> {
+    fn handle_route_logged_in(
+        &self,
+        request: &Request,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Response> {
         rouille::router!(request,
             (GET) ["/webman/login.cgi"] => {
                 Ok(rouille::Response::json(&String::from(r#"{"SynoToken", ""}"#)).with_additional_header("Content-Type","application/json; charset=utf-8").with_status_code(200))
              },
+            (GET) ["/stats/disk"] => {
+                self.disk_stats()
+            },
+            (POST) ["/admin/reextract-assets"] => {
+                self.reextract_assets(request)
+            },
+            (GET) ["/admin/sessions"] => {
+                self.admin_sessions(request)
+            },
             _ => {
-                if request.raw_url().contains(env::SYNOPKG_WEB_UI_HOME).not() {
-                    return Ok(rouille::Response::redirect_307(env::SYNOPKG_WEB_UI_HOME))
-                }
-                let mut cmd = std::process::Command::new(env::SYNOPKG_CLI_WEB);
-                cmd.current_dir(env::SYNOPKG_PKGDEST);
-                cmd.envs(&self.envs)
-                .env("SERVER_SOFTWARE", "rust")
-                .env("SERVER_PROTOCOL", "HTTP/1.1")
-                .env("HTTP_HOST", &request.remote_addr().to_string())
-                .env("GATEWAY_INTERFACE", "CGI/1.1")
-                .env("REQUEST_METHOD", request.method())
-                .env("QUERY_STRING", request.raw_query_string())
-                .env("REQUEST_URI", request.raw_url())
-                .env("PATH_INFO", &request.url())
-                .env("SCRIPT_NAME", ".")
-                .env("SCRIPT_FILENAME", &request.url())
-                .env("SERVER_PORT", self.port.to_string())
-                .env("REMOTE_ADDR", request.remote_addr().to_string())
-                .env("SERVER_NAME", request.remote_addr().to_string())
-                .uid(self.uid)
-                .gid(self.gid)
-                .stdout(Stdio::piped())
-                .stdin(Stdio::piped());
-
-                if !self.debug {
-                    cmd.stderr(Stdio::null());
-                }
-
-                for ele in request.headers() {
-                    let k = ele.0.to_uppercase();
-                    let v = ele.1;
-                    if k == "PROXY" {
-                        continue
-                    }
-                    if v.is_empty().not() {
-                        cmd.env(format!("HTTP_{}", k), v);
-                    }
-                }
-
-                if request.header("Content-Type").unwrap_or_default().is_empty().not() {
-                    cmd.env(
-                        "CONTENT_TYPE",
-                        request.header("Content-Type").context("[XunleiPanelServer] Failed to set Content-Type header")?,
-                    );
-                }
-
-                if request.header("content-type").unwrap_or_default().is_empty().not() {
-                    cmd.env(
-                        "CONTENT_TYPE",
-                        request.header("content-type").context("[XunleiPanelServer] Failed to set content-type header")?,
-                    );
+                if request.raw_url().contains(&self.web_ui_home).not() {
+                    return Ok(rouille::Response::redirect_307(format!("{}{}", self.base_path, self.web_ui_home)))
                 }
+                self.fallback.respond(self, request, deadline)
+            }
+        )
+    }
 
-                if request.header("Content-Length").unwrap_or_default().is_empty().not() {
-                    cmd.env(
-                        "CONTENT_LENGTH",
-                        request.header("Content-Length").context("[XunleiPanelServer] Failed to set Content-Length header")?,
-                    );
-                }
+    // Round-trip a request to the CGI, which only answers once the backend is up
+    fn readiness(&self, request: &Request, deadline: Option<Instant>) -> anyhow::Result<Response> {
+        let probe = Request::fake_http_from(
+            *request.remote_addr(),
+            "GET",
+            self.probe_url.clone(),
+            Vec::new(),
+            Vec::new(),
+        );
+        let status_code = match self.fallback.respond(self, &probe, deadline) {
+            Ok(response) => response.status_code,
+            Err(e) => {
+                log::debug!("[XunleiPanelServer] Readiness probe failed: {:#}", e);
+                503
+            }
+        };
+        match (200..400).contains(&status_code) {
+            true => Ok(Response::text("ready")),
+            false => Ok(Response::text(format!(
+                "not ready: {} answered {}",
+                self.probe_url, status_code
+            ))
+            .with_status_code(503)),
+        }
+    }
 
-                let mut child = cmd.spawn()?;
+    // GET a CGI path as a local request, for the launcher's own calls
+    fn request_cgi(&self, path: &str) -> anyhow::Result<u16> {
+        let request = Request::fake_http_from(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            "GET",
+            path.to_owned(),
+            Vec::new(),
+            Vec::new(),
+        );
+        let deadline = self.request_timeout.map(|timeout| Instant::now() + timeout);
+        let response = self
+            .fallback
+            .respond(self, &request, deadline)
+            .with_context(|| format!("[XunleiPanelServer] The CGI request {} failed", path))?;
+        Ok(response.status_code)
+    }
 
-                if let Some(mut body) = request.data() {
-                    std::io::copy(&mut body, child.stdin.take().as_mut().context("[XunleiPanelServer] Failed to read CGI stdin")?)?;
-                }
+    fn disk_stats(&self) -> anyhow::Result<Response> {
+        #[derive(serde::Serialize)]
+        struct DiskStats {
+            download_path: PathBuf,
+            #[serde(flatten)]
+            usage: util::DiskUsage,
+            mount_bind_download_path: PathBuf,
+            mounted: bool,
+        }
 
-                {
-                    let mut stdout = std::io::BufReader::new(child.stdout.take().context("[XunleiPanelServer] Failed to reader CGI stdout")?);
-
-                    let mut headers = Vec::new();
-                    let mut status_code = 200;
-                    for header_res in std::io::BufRead::lines(stdout.by_ref()) {
-                        let header = header_res?;
-                        if header.is_empty() {
-                            break;
-                        }
+        Ok(Response::json(&DiskStats {
+            download_path: self.download_path.clone(),
+            usage: util::disk_usage(&self.download_path)?,
+            mount_bind_download_path: self.mount_bind_download_path.clone(),
+            mounted: util::is_mounted(&self.mount_bind_download_path)?,
+        }))
+    }
 
-                        let (header, val) = header.split_once(':').context("[XunleiPanelServer] Failed to split_once header")?;
-                        let val = &val[1..];
+    // The /admin routes don't exist without a token, and answer 403 to a wrong one
+    fn admin_authorized(&self, request: &Request) -> Result<(), Response> {
+        let Some(token) = &self.admin_token else {
+            return Err(Response::empty_404());
+        };
+        // Compare in constant time, the token guards destructive actions
+        let given = request.header("X-Admin-Token").unwrap_or_default();
+        let authorized = token.len() == given.len()
+            && token
+                .bytes()
+                .zip(given.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0;
+        match authorized {
+            true => Ok(()),
+            false => Err(Response::text("Forbidden").with_status_code(403)),
+        }
+    }
 
-                        if header == "Status" {
-                            status_code = val[0..3]
-                                .parse()
-                                .expect("Status returned by CGI program is invalid");
-                        } else {
-                            headers.push((header.to_owned().into(), val.to_owned().into()));
-                        }
-                    }
-                    Ok(rouille::Response{status_code,headers,data:rouille::ResponseBody::from_reader(stdout),upgrade:None,})
-                }
-            }
+    // With --login-csp-nonce only the script tags of this response carry the nonce the policy allows
+    fn login_response(&self) -> Response {
+        if self.login_csp_nonce.not() {
+            return Response::html(self.login_page.clone());
+        }
+        let nonce = format!("{:032x}", rand::random::<u128>());
+        let page = self
+            .login_page
+            .replace("<script ", &format!(r#"<script nonce="{}" "#, nonce));
+        Response::html(page).with_unique_header(
+            "Content-Security-Policy",
+            format!("script-src 'nonce-{}'", nonce),
         )
     }
-}
 
-impl Running for XunleiPanelServer {
-    fn run(self) -> anyhow::Result<()> {
-        let sessions_storage: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
-        let listen = format!("{}:{}", self.host, self.port);
-        log::info!(
-            "[XunleiLauncher] Start Xunlei Pannel UI, listening on {}",
-            listen
-        );
-        rouille::start_server(listen, move |request| {
-            rouille::log(request, io::stdout(), || {
-                rouille::session::session(request, "XUNLEI_SID", 3600, |session| {
-                    let mut session_data = if session.client_has_sid() {
-                        sessions_storage.lock().unwrap().get(session.id()).cloned()
-                    } else {
-                        None
-                    };
-
-                    let response = self.handle_route(request, &mut session_data);
-
-                    if let Some(d) = session_data {
-                        sessions_storage
-                            .lock()
-                            .unwrap()
-                            .insert(session.id().to_owned(), d);
-                    } else if session.client_has_sid() {
-                        sessions_storage.lock().unwrap().remove(session.id());
-                    }
+    // The live sessions and, with --session-accounting, the bytes of each user
+    fn admin_sessions(&self, request: &Request) -> anyhow::Result<Response> {
+        if let Err(response) = self.admin_authorized(request) {
+            return Ok(response);
+        }
 
-                    match response {
-                        Ok(res) => res,
-                        Err(e) => Response::text(format!("An error occurred {}", e)),
-                    }
-                })
+        #[derive(serde::Serialize)]
+        struct SessionInfo {
+            user: Option<String>,
+            idle_secs: u64,
+        }
+        #[derive(serde::Serialize)]
+        struct Sessions {
+            sessions: Vec<SessionInfo>,
+            traffic: Option<HashMap<String, Traffic>>,
+        }
+        let sessions = self
+            .sessions
+            .list()
+            .into_iter()
+            .map(|session| SessionInfo {
+                user: session.user.clone(),
+                idle_secs: session.last_active.elapsed().as_secs(),
             })
-        });
+            .collect();
+        let traffic = self
+            .traffic
+            .as_ref()
+            .map(|traffic| traffic.lock().unwrap().clone());
+        Ok(Response::json(&Sessions { sessions, traffic }))
     }
-}
 
-impl From<XunleiLauncher> for XunleiPanelServer {
-    fn from(launcher: XunleiLauncher) -> Self {
-        let envs = launcher.envs().unwrap();
-        Self {
-            auth_user: launcher.auth_user.clone(),
-            auth_password: launcher.auth_password.clone(),
-            host: launcher.host,
-            port: launcher.port,
-            envs,
-            debug: launcher.debug,
-            uid: launcher.uid,
-            gid: launcher.gid,
+    // Re-extract the assets and have the supervisor restart the backend on them
+    fn reextract_assets(&self, request: &Request) -> anyhow::Result<Response> {
+        if let Err(response) = self.admin_authorized(request) {
+            return Ok(response);
+        }
+
+        if cfg!(feature = "embed").not() {
+            return Ok(self.error_response(
+                request,
+                501,
+                "Re-extracting needs a build with the embedded assets",
+            ));
+        }
+        log::warn!(
+            "[XunleiPanelServer] Re-extract assets requested by {}",
+            request.remote_addr()
+        );
+        let files = xunlei_asset::reextract(Path::new(env::SYNOPKG_PKGDEST), self.uid, self.gid)?;
+        self.restart_backend.store(true, Ordering::SeqCst);
+
+        #[derive(serde::Serialize)]
+        struct Reextracted {
+            files: Vec<String>,
+            restart_requested: bool,
         }
+        Ok(Response::json(&Reextracted {
+            files,
+            restart_requested: true,
+        }))
+    }
+
+    // Render the error page, the request id in the page matches the logged one
+    fn error_response(
+        &self,
+        request: &Request,
+        status: u16,
+        cause: impl std::fmt::Display,
+    ) -> Response {
+        let request_id = format!("{:016x}", rand::random::<u64>());
+        log::error!(
+            "[XunleiPanelServer] {} {} ({}): {:#}",
+            status,
+            request.raw_url(),
+            request_id,
+            cause
+        );
+        let reason = match status {
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Error",
+        };
+        let html = self
+            .error_page
+            .replace("{{status}}", &status.to_string())
+            .replace("{{reason}}", reason)
+            .replace("{{request_id}}", &request_id);
+        Response::html(html).with_status_code(status)
+    }
+
+    // The CGI variables of the origin the client reached, over the ones taken from the request
+    fn cgi_origin_env(&self, request: &Request) -> Vec<(&'static str, String)> {
+        let mut origins = Vec::new();
+        // Only a trusted proxy may report the public host and scheme
+        if self.trusted_proxies.contains(&request.remote_addr().ip()) {
+            origins.push(forwarded_origin(
+                request.header("X-Forwarded-Host"),
+                request.header("X-Forwarded-Proto"),
+            ));
+        }
+        // A configured external URL is the origin clients reach the panel on, whatever the request says
+        if let Some((proto, host)) = self.external_url.as_deref().and_then(external_origin) {
+            origins.push(forwarded_origin(Some(host), Some(proto)));
+        }
+
+        // The scheme of the panel listener, unless an origin above reports it
+        let mut envs = Vec::new();
+        let mut https = request.is_secure();
+        for origin in origins {
+            if let Some((host, name, port)) = origin.host {
+                envs.retain(|(k, _)| {
+                    ["HTTP_HOST", "SERVER_NAME", "SERVER_PORT"]
+                        .contains(k)
+                        .not()
+                });
+                envs.push(("HTTP_HOST", host.to_owned()));
+                envs.push(("SERVER_NAME", name.to_owned()));
+                envs.push(("SERVER_PORT", port.to_string()));
+            }
+            https = origin.https.unwrap_or(https);
+        }
+        match https {
+            true => envs.extend([
+                ("HTTPS", "on".to_owned()),
+                ("REQUEST_SCHEME", "https".to_owned()),
+            ]),
+            false => envs.push(("REQUEST_SCHEME", "http".to_owned())),
+        }
+        envs
+    }
+
+    // Bridge the request to the xunlei CGI program
+    fn handle_cgi(&self, request: &Request, deadline: Option<Instant>) -> anyhow::Result<Response> {
+        let url = request.url();
+        let (script_name, path_info) = cgi_script_mapping(&self.web_ui_home, &url);
+        let mut cmd = std::process::Command::new(&self.cgi_program);
+        cmd.current_dir(&self.cgi_working_dir);
+        cmd.envs(&self.envs)
+            .env("SERVER_SOFTWARE", "rust")
+            .env("SERVER_PROTOCOL", "HTTP/1.1")
+            .env("HTTP_HOST", request.remote_addr().to_string())
+            .env("GATEWAY_INTERFACE", "CGI/1.1")
+            .env("REQUEST_METHOD", request.method())
+            .env("QUERY_STRING", request.raw_query_string())
+            .env("REQUEST_URI", request.raw_url())
+            .env("PATH_INFO", path_info)
+            .env("SCRIPT_NAME", script_name)
+            .env("SCRIPT_FILENAME", &self.cgi_program)
+            .env("SERVER_PORT", self.port.to_string())
+            .env("REMOTE_ADDR", request.remote_addr().to_string())
+            .env("SERVER_NAME", request.remote_addr().to_string())
+            .uid(self.uid)
+            .gid(self.gid)
+            .stdout(Stdio::piped())
+            .stdin(Stdio::piped());
+
+        // Collected to explain a CGI failing without output, and passed through in debug mode
+        cmd.stderr(Stdio::piped());
+
+        for ele in request.headers() {
+            let k = ele.0.to_uppercase();
+            let v = ele.1;
+            if k == "PROXY" {
+                continue;
+            }
+            if v.is_empty().not() {
+                cmd.env(format!("HTTP_{}", k), v);
+            }
+        }
+
+        cmd.envs(self.cgi_origin_env(request));
+
+        if request
+            .header("Content-Type")
+            .unwrap_or_default()
+            .is_empty()
+            .not()
+        {
+            cmd.env(
+                "CONTENT_TYPE",
+                request
+                    .header("Content-Type")
+                    .context("[XunleiPanelServer] Failed to set Content-Type header")?,
+            );
+        }
+
+        if request
+            .header("content-type")
+            .unwrap_or_default()
+            .is_empty()
+            .not()
+        {
+            cmd.env(
+                "CONTENT_TYPE",
+                request
+                    .header("content-type")
+                    .context("[XunleiPanelServer] Failed to set content-type header")?,
+            );
+        }
+
+        if request
+            .header("Content-Length")
+            .unwrap_or_default()
+            .is_empty()
+            .not()
+        {
+            cmd.env(
+                "CONTENT_LENGTH",
+                request
+                    .header("Content-Length")
+                    .context("[XunleiPanelServer] Failed to set Content-Length header")?,
+            );
+        }
+
+        if deadline.is_some() {
+            // The watchdog kills the whole group, including processes forked by the CGI program
+            cmd.process_group(0);
+        }
+
+        let mut attempt = 0;
+        let mut child = loop {
+            match cmd.spawn() {
+                Ok(child) => break child,
+                // Fork fails transiently when the system is short of processes or memory
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(nix::libc::EAGAIN) | Some(nix::libc::ENOMEM)
+                    ) =>
+                {
+                    if attempt >= self.cgi_spawn_retries {
+                        return Ok(self.error_response(request, 503, e));
+                    }
+                    attempt += 1;
+                    log::warn!(
+                        "[XunleiPanelServer] Failed to spawn CGI process: {}, retry {}/{}",
+                        e,
+                        attempt,
+                        self.cgi_spawn_retries
+                    );
+                    std::thread::sleep(CGI_SPAWN_BACKOFF * attempt);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let mut stdin = child
+            .stdin
+            .take()
+            .context("[XunleiPanelServer] Failed to read CGI stdin")?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("[XunleiPanelServer] Failed to reader CGI stdout")?;
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| CgiStderr::spawn(stderr, self.debug));
+        let (watchdog, exit_status) = CgiWatchdog::spawn(child, deadline);
+
+        let mut stdout = std::io::BufReader::with_capacity(
+            self.cgi_buffer_size,
+            DeadlineReader {
+                inner: stdout,
+                deadline,
+                idle: None,
+                watchdog: Some(watchdog),
+            },
+        );
+
+        // Feed the body while the headers are read, a CGI may start answering before it has
+        // consumed a large upload. The transports send the interim 100 Continue to clients
+        // that asked for it once the body is first read. The feeder is never waited for, a
+        // stalled client must not keep the response past the deadline
+        let streamed = listener::take_body(request);
+        // The stock rouille server keeps the body in the request, owned by a copy sharing it
+        let owned = streamed
+            .is_none()
+            .then(|| request.remove_prefix(""))
+            .flatten();
+        let received = Traffic::received();
+        std::thread::spawn(move || {
+            let body = streamed.or_else(|| owned.as_ref().and_then(request_body));
+            if let Some(body) = body {
+                // Count through Take, whose remaining limit survives a failed copy
+                let mut body = DeadlineReader {
+                    inner: body,
+                    deadline,
+                    idle: None,
+                    watchdog: None,
+                }
+                .take(u64::MAX);
+                if let Err(e) = std::io::copy(&mut body, &mut stdin) {
+                    log::debug!(
+                        "[XunleiPanelServer] Stopped feeding the request body to the CGI: {}",
+                        e
+                    );
+                }
+                received.fetch_add(u64::MAX - body.limit(), Ordering::Relaxed);
+            }
+        });
+
+        let mut headers: Vec<(Cow<'static, str>, Cow<'static, str>)> = Vec::new();
+        let mut status_code = 200;
+        let mut headers_end = false;
+        for header_res in std::io::BufRead::lines(stdout.by_ref()) {
+            let header = header_res?;
+            if header.is_empty() {
+                headers_end = true;
+                break;
+            }
+
+            let (header, val) = header
+                .split_once(':')
+                .context("[XunleiPanelServer] Failed to split_once header")?;
+            let val = val.strip_prefix(' ').unwrap_or(val);
+
+            if header == "Status" {
+                status_code = val
+                    .get(..3)
+                    .and_then(|code| code.parse().ok())
+                    .filter(|code| (100..600).contains(code))
+                    .with_context(|| {
+                        format!("[XunleiPanelServer] Invalid CGI Status: {:?}", val)
+                    })?;
+            } else {
+                headers.push((header.to_owned().into(), val.to_owned().into()));
+            }
+        }
+        // Past the headers the deadline only bounds each wait for more of the body, so that long
+        // downloads keep streaming
+        stdout.get_mut().idle = self.request_timeout;
+
+        // Nothing at all before the end of the output is a failed CGI, not an empty 200
+        if headers_end.not() && headers.is_empty() && status_code == 200 {
+            drop(stdout);
+            let status = exit_status
+                .recv_timeout(CGI_EXIT_WAIT)
+                .map(|status| status.to_string())
+                .unwrap_or_else(|_| String::from("still running"));
+            let stderr = stderr.map(CgiStderr::tail).unwrap_or_default();
+            log::error!(
+                "[XunleiPanelServer] CGI produced no response headers for {}, exit: {}, stderr: {:?}",
+                url,
+                status,
+                stderr
+            );
+            return Ok(self.error_response(
+                request,
+                502,
+                "The xunlei CGI program produced no response",
+            ));
+        }
+
+        // Drop the hop-by-hop headers and any header the CGI listed in its Connection header,
+        // the panel manages its own connections with the client
+        let connection_options = headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, v)| v.split(',').map(|v| v.trim().to_owned()))
+            .collect::<Vec<_>>();
+        headers.retain(|(k, _)| {
+            HOP_BY_HOP_HEADERS
+                .iter()
+                .copied()
+                .chain(connection_options.iter().map(String::as_str))
+                .any(|h| k.eq_ignore_ascii_case(h))
+                .not()
+        });
+
+        // Point absolute redirects at the internal address to the external URL instead
+        if let Some(external_url) = &self.external_url {
+            let internal = [
+                request.header("Host").map(str::to_owned),
+                Some(request.remote_addr().to_string()),
+                Some(match &self.host {
+                    ListenHost::Ip(addr) => SocketAddr::new(*addr, self.port).to_string(),
+                    ListenHost::Name(name) => format!("{}:{}", name, self.port),
+                }),
+            ];
+            for (_, value) in headers.iter_mut().filter(|(k, _)| {
+                k.eq_ignore_ascii_case("Location") || k.eq_ignore_ascii_case("Content-Location")
+            }) {
+                if let Some(rewritten) = rewrite_internal_url(value, external_url, &internal) {
+                    *value = rewritten.into();
+                }
+            }
+        }
+
+        // The CGI hands the body over to the panel, which serves the file directly
+        let sendfile = headers
+            .iter()
+            .find(|(k, _)| {
+                k.eq_ignore_ascii_case("X-Accel-Redirect") || k.eq_ignore_ascii_case("X-Sendfile")
+            })
+            .map(|(_, v)| PathBuf::from(v.trim()));
+        if let Some(path) = sendfile {
+            drop(stdout);
+            return self.send_file(request, &path, headers);
+        }
+
+        let no_store = match self.cgi_cache_policy {
+            CgiCachePolicy::NoStoreHtml => headers.iter().any(|(k, v)| {
+                k.eq_ignore_ascii_case("Content-Type")
+                    && v.trim_start().to_ascii_lowercase().starts_with("text/html")
+            }),
+            CgiCachePolicy::NoStore => true,
+            CgiCachePolicy::Passthrough => false,
+        };
+        if no_store {
+            headers.retain(|(k, _)| {
+                CACHE_HEADERS
+                    .iter()
+                    .any(|h| k.eq_ignore_ascii_case(h))
+                    .not()
+            });
+            headers.push(("Cache-Control".into(), "no-store".into()));
+        }
+
+        Ok(rouille::Response {
+            status_code,
+            headers,
+            data: rouille::ResponseBody::from_reader(stdout),
+            upgrade: None,
+        })
+    }
+
+    // Serve a file under the download directories, honouring a single byte range
+    fn send_file(
+        &self,
+        request: &Request,
+        path: &Path,
+        cgi_headers: Vec<(Cow<'static, str>, Cow<'static, str>)>,
+    ) -> anyhow::Result<Response> {
+        let allowed = std::fs::canonicalize(path).ok().filter(|path| {
+            [&self.download_path, &self.mount_bind_download_path]
+                .iter()
+                .filter_map(|root| std::fs::canonicalize(root).ok())
+                .any(|root| path.starts_with(root))
+        });
+        let Some(path) = allowed else {
+            log::warn!(
+                "[XunleiPanelServer] Refuse to send {} outside the download directories",
+                path.display()
+            );
+            return Ok(Response::text("Forbidden").with_status_code(403));
+        };
+        let mut file = std::fs::File::open(&path)?;
+        let size = file.metadata()?.len();
+
+        let mut headers = cgi_headers
+            .into_iter()
+            .filter(|(k, _)| {
+                ["X-Accel-Redirect", "X-Sendfile", "Content-Length", "Status"]
+                    .iter()
+                    .any(|h| k.eq_ignore_ascii_case(h))
+                    .not()
+            })
+            .collect::<Vec<_>>();
+        if headers
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case("Content-Type"))
+            .not()
+        {
+            headers.push(("Content-Type".into(), "application/octet-stream".into()));
+        }
+        headers.push(("Accept-Ranges".into(), "bytes".into()));
+
+        let (status_code, start, len) = match request.header("Range").map(|r| byte_range(r, size)) {
+            None | Some(ByteRange::Ignored) => (200, 0, size),
+            Some(ByteRange::Satisfiable(start, end)) => {
+                headers.push((
+                    "Content-Range".into(),
+                    format!("bytes {}-{}/{}", start, end, size).into(),
+                ));
+                (206, start, end - start + 1)
+            }
+            Some(ByteRange::Unsatisfiable) => {
+                return Ok(Response::text("Range Not Satisfiable")
+                    .with_status_code(416)
+                    .with_additional_header("Content-Range", format!("bytes */{}", size)));
+            }
+        };
+        file.seek(io::SeekFrom::Start(start))?;
+        Ok(rouille::Response {
+            status_code,
+            headers,
+            data: rouille::ResponseBody::from_reader_and_size(file.take(len), len as usize),
+            upgrade: None,
+        })
+    }
+}
+
+enum ByteRange {
+    // Missing, malformed or multiple ranges, the whole file is sent
+    Ignored,
+    // Inclusive first and last byte
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+// Parse a Range header holding a single bytes range, as "first-", "first-last" or "-suffix"
+fn byte_range(header: &str, size: u64) -> ByteRange {
+    let Some(range) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Ignored;
+    };
+    if range.contains(',') {
+        return ByteRange::Ignored;
+    }
+    let Some((first, last)) = range.trim().split_once('-') else {
+        return ByteRange::Ignored;
+    };
+    let (first, last) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(size.saturating_sub(1))),
+        (Ok(first), Err(_)) if last.is_empty() => (first, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return ByteRange::Ignored,
+    };
+    match first < size {
+        true => ByteRange::Satisfiable(first, last),
+        false => ByteRange::Unsatisfiable,
+    }
+}
+
+// CGI/1.1 script mapping of a decoded url path: SCRIPT_NAME is the matched script
+// (the web UI home without its trailing slash) and PATH_INFO the trailing path
+fn cgi_script_mapping<'a>(web_ui_home: &'a str, url: &'a str) -> (&'a str, &'a str) {
+    let script_name = web_ui_home.trim_end_matches('/');
+    match url.strip_prefix(script_name) {
+        Some(path_info) if path_info.is_empty() || path_info.starts_with('/') => {
+            (script_name, path_info)
+        }
+        _ => ("", url),
+    }
+}
+
+// Public origin reported through X-Forwarded-Host/X-Forwarded-Proto
+struct ForwardedOrigin<'a> {
+    // Host header, server name and port
+    host: Option<(&'a str, &'a str, u16)>,
+    // None when the proxy didn't report the scheme
+    https: Option<bool>,
+}
+
+// Parse the forwarded headers, a proxy chain appends values so the first one is the client facing
+fn forwarded_origin<'a>(host: Option<&'a str>, proto: Option<&'a str>) -> ForwardedOrigin<'a> {
+    let first = |v: &'a str| v.split(',').next().unwrap_or_default().trim();
+    let https = proto
+        .map(first)
+        .filter(|proto| proto.is_empty().not())
+        .map(|proto| proto.eq_ignore_ascii_case("https"));
+    let default_port = if https == Some(true) { 443 } else { 80 };
+    let host = host
+        .map(first)
+        .filter(|host| host.is_empty().not())
+        .map(|host| {
+            // The port follows the last colon, unless that colon belongs to an IPv6 address
+            match host.rsplit_once(':') {
+                Some((name, port)) if name.contains(':').not() || name.ends_with(']') => {
+                    match port.parse::<u16>() {
+                        Ok(port) => (host, name, port),
+                        Err(_) => (host, host, default_port),
+                    }
+                }
+                _ => (host, host, default_port),
+            }
+        });
+    ForwardedOrigin { host, https }
+}
+
+// Scheme and authority of the configured external URL
+fn external_origin(url: &str) -> Option<(&str, &str)> {
+    let (proto, rest) = url.split_once("://")?;
+    Some((proto, rest.split('/').next().unwrap_or_default()))
+}
+
+// Rebase an absolute URL on one of the internal authorities onto the external URL
+fn rewrite_internal_url(
+    url: &str,
+    external_url: &str,
+    internal: &[Option<String>],
+) -> Option<String> {
+    let rest = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    internal
+        .iter()
+        .flatten()
+        .any(|internal| internal.eq_ignore_ascii_case(authority))
+        .then(|| format!("{}{}", external_url, path))
+}
+
+// Reaps the CGI process once the response is complete, killing it first when a deadline
+// passes, and hands back its exit status
+struct CgiWatchdog {
+    // Each message moves the deadline, dropping the sender tells that the response is complete
+    deadline: mpsc::Sender<Instant>,
+}
+
+impl CgiWatchdog {
+    fn spawn(
+        mut child: std::process::Child,
+        deadline: Option<Instant>,
+    ) -> (Self, mpsc::Receiver<std::process::ExitStatus>) {
+        let (tx, rx) = mpsc::channel();
+        let (status_tx, status_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut deadline = deadline;
+            let timed_out = loop {
+                let received = match deadline {
+                    Some(deadline) => {
+                        rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                    None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(moved) => deadline = Some(moved),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break false,
+                    Err(mpsc::RecvTimeoutError::Timeout) => break true,
+                }
+            };
+            if timed_out {
+                log::warn!(
+                    "[XunleiPanelServer] Kill CGI process {}, the request timed out",
+                    child.id()
+                );
+                let _ = nix::sys::signal::killpg(
+                    Pid::from_raw(child.id() as i32),
+                    nix::sys::signal::SIGKILL,
+                );
+            }
+            if let Ok(status) = child.wait() {
+                let _ = status_tx.send(status);
+            }
+        });
+        (Self { deadline: tx }, status_rx)
+    }
+}
+
+// Keeps the end of the CGI stderr, echoing it in debug mode
+struct CgiStderr {
+    tail: mpsc::Receiver<Vec<u8>>,
+}
+
+impl CgiStderr {
+    fn spawn(mut stderr: std::process::ChildStderr, echo: bool) -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut tail = Vec::new();
+            let mut buf = [0; 4096];
+            while let Ok(read) = stderr.read(&mut buf) {
+                if read == 0 {
+                    break;
+                }
+                if echo {
+                    let _ = io::Write::write_all(&mut io::stderr(), &buf[..read]);
+                }
+                tail.extend_from_slice(&buf[..read]);
+                let excess = tail.len().saturating_sub(CGI_STDERR_TAIL);
+                tail.drain(..excess);
+            }
+            let _ = tx.send(tail);
+        });
+        Self { tail: rx }
+    }
+
+    // The end of stderr once the CGI closed it, empty when it doesn't close soon
+    fn tail(self) -> String {
+        let tail = self.tail.recv_timeout(CGI_EXIT_WAIT).unwrap_or_default();
+        String::from_utf8_lossy(&tail).trim_end().to_owned()
+    }
+}
+
+// Fails reads after the deadline, which moves `idle` ahead on each read once set. Dropping it
+// releases the CGI watchdog
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Option<Instant>,
+    idle: Option<Duration>,
+    watchdog: Option<CgiWatchdog>,
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "[XunleiPanelServer] Request timed out",
+            ));
+        }
+        let read = self.inner.read(buf)?;
+        if let Some(idle) = self.idle {
+            let deadline = Instant::now() + idle;
+            self.deadline = Some(deadline);
+            if let Some(watchdog) = &self.watchdog {
+                let _ = watchdog.deadline.send(deadline);
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl XunleiPanelServer {
+    // Whatever route answers, the response has to start before the deadline
+    fn within_deadline(
+        &self,
+        request: &Request,
+        deadline: Option<Instant>,
+        response: anyhow::Result<Response>,
+    ) -> anyhow::Result<Response> {
+        match deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            true => Ok(self.error_response(request, 504, "Request timed out")),
+            false => response,
+        }
+    }
+
+    // Bind the listening socket, the returned server still has to be run
+    fn serve(
+        mut self,
+    ) -> anyhow::Result<PanelServer<impl Fn(&Request) -> Response + Send + Sync + 'static>> {
+        if let Some(path) = &self.error_page_path {
+            self.error_page = std::fs::read_to_string(path)
+                .context(format!(
+                    "[XunleiPanelServer] Failed to read error page: {}",
+                    path.display()
+                ))
+                .context(ErrorKind::Config)?;
+        }
+        if let Some(path) = &self.auth_file {
+            let credentials = load_auth_file(path).context(ErrorKind::Config)?;
+            log::info!(
+                "[XunleiPanelServer] Loaded {} credentials from {}",
+                credentials.len(),
+                path.display()
+            );
+        }
+        let tls = self.tls_config()?;
+        let storage = self.sessions.clone();
+        let prune_interval = self.session_prune_interval;
+        let stats = self.stats.clone();
+        if let Some(parent) = Path::new(env::SESSIONS_FILE).parent() {
+            if self.session_store == SessionStoreKind::File && parent.exists().not() {
+                util::create_dir_all(parent, 0o777)?;
+            }
+        }
+        std::thread::spawn(move || loop {
+            std::thread::sleep(prune_interval);
+            let (pruned, left) = storage.prune();
+            stats.sessions.store(left, Ordering::Relaxed);
+            log::debug!(
+                "[XunleiPanelServer] Pruned {} expired sessions, {} left",
+                pruned,
+                left
+            );
+        });
+        let listen = SocketAddr::new(self.host.resolve().context(ErrorKind::Config)?, self.port);
+        let listen_options = self.listen_options.clone();
+        let activated = listener::activated(&listen_options)?;
+        match activated {
+            Some(ref listener) => log::info!(
+                "[XunleiLauncher] Start Xunlei Pannel UI, listening on {} (socket activation)",
+                listener
+                    .local_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_default()
+            ),
+            None => log::info!(
+                "[XunleiLauncher] Start Xunlei Pannel UI, listening on {}://{}",
+                if tls.is_some() { "https" } else { "http" },
+                listen
+            ),
+        }
+        let body_limit =
+            listener::BodyLimit::new(self.max_request_body, self.max_login_body, &self.base_path)
+                .with_request_timeout(self.request_timeout);
+        #[cfg(feature = "http2")]
+        let http2 = self.http2;
+        let handler = move |request: &Request| {
+            self.stats.requests.fetch_add(1, Ordering::Relaxed);
+            let Some(guard) = self.drain.enter() else {
+                return self.error_response(request, 503, "The panel is shutting down");
+            };
+            let response = rouille::log(request, io::stdout(), || {
+                rouille::session::session(
+                    request,
+                    SESSION_COOKIE,
+                    SESSION_LIFETIME.as_secs(),
+                    |session| {
+                        let mut session_data = match session.client_has_sid() {
+                            true => self.sessions.get(session.id()),
+                            false => None,
+                        };
+
+                        let deadline = self.request_timeout.map(|timeout| Instant::now() + timeout);
+                        Traffic::take_received();
+                        let response = self.handle_route(request, &mut session_data, deadline);
+                        let response = self.within_deadline(request, deadline, response);
+                        let user = session_data.as_ref().and_then(|d| d.user.clone());
+
+                        let mut rejected = false;
+                        if let Some(mut d) = session_data {
+                            d.last_active = Instant::now();
+                            // Refuse a login there is no room for, a session without a user is just not kept
+                            rejected = self.sessions.keep(session.id(), d).not() && user.is_some();
+                        } else if session.client_has_sid() {
+                            self.sessions.remove(session.id());
+                        }
+                        self.stats
+                            .sessions
+                            .store(self.sessions.count(), Ordering::Relaxed);
+
+                        let response = match (response, rejected) {
+                            (_, true) => self.error_response(
+                                request,
+                                503,
+                                "Too many panel sessions, try again later",
+                            ),
+                            (Ok(res), false) => res,
+                            (Err(e), false) => self.error_response(request, 500, e),
+                        };
+                        match (&self.traffic, user) {
+                            (Some(traffic), Some(user)) => {
+                                Traffic::account(traffic, user, response)
+                            }
+                            _ => response,
+                        }
+                    },
+                )
+            });
+            guard.attach(response)
+        };
+
+        // Never the stock rouille server: it has no timeouts to give up on a stalled client, and
+        // holds the request body while it writes the response, which a CGI answering before it
+        // consumed its input waits on
+        let listener = match activated {
+            Some(listener) => listener,
+            None => {
+                log::info!(
+                    "[XunleiPanelServer] Listen backlog: {}, SO_REUSEADDR: {}, SO_REUSEPORT: {}, TCP_NODELAY: {}",
+                    listen_options.backlog.unwrap_or(128),
+                    listen_options.reuse_addr,
+                    listen_options.reuse_port,
+                    listen_options.nodelay
+                );
+                listener::bind(listen, &listen_options)?
+            }
+        };
+
+        match (listener, tls) {
+            #[cfg(feature = "http2")]
+            (listener, tls) if http2 => {
+                log::info!("[XunleiPanelServer] Serve HTTP/2 and HTTP/1.1 with hyper");
+                Ok(PanelServer::Http2(crate::http2::Server::from_listener(
+                    listener, tls, body_limit, handler,
+                )?))
+            }
+            (listener, Some(config)) => Ok(PanelServer::Tls(tls::Server::from_listener(
+                listener, config, body_limit, handler,
+            ))),
+            (listener, None) => Ok(PanelServer::Listener(listener::Server::from_listener(
+                listener, body_limit, handler,
+            ))),
+        }
+    }
+
+    fn tls_config(&self) -> anyhow::Result<Option<Arc<rustls::ServerConfig>>> {
+        if self.tls_cert.is_empty() && self.tls_key.is_empty() {
+            return Ok(None);
+        }
+        if self.tls_cert.len() != self.tls_key.len() {
+            return Err(anyhow::anyhow!(
+                "[XunleiPanelServer] {} --tls-cert given for {} --tls-key",
+                self.tls_cert.len(),
+                self.tls_key.len()
+            )
+            .context(ErrorKind::Config));
+        }
+        let identities = self
+            .tls_cert
+            .iter()
+            .zip(&self.tls_key)
+            .map(|((host, cert), key)| tls::TlsIdentity {
+                host: host.clone(),
+                cert: cert.clone(),
+                key: key.clone(),
+            })
+            .collect::<Vec<_>>();
+        tls::server_config(&identities, self.min_tls_version, self.tls_cipher_policy).map(Some)
+    }
+}
+
+impl Running for XunleiPanelServer {
+    fn run(self) -> anyhow::Result<()> {
+        self.serve()?.run();
+        Ok(())
+    }
+}
+
+impl From<XunleiLauncher> for XunleiPanelServer {
+    fn from(launcher: XunleiLauncher) -> Self {
+        let envs = launcher.envs().unwrap();
+        Self {
+            auth_user: launcher.auth_user.clone(),
+            auth_password: launcher.auth_password.clone(),
+            auth_user_name: launcher.auth_user_name.clone(),
+            auth_file: launcher.auth_file,
+            max_login_body: launcher.max_login_body,
+            max_request_body: launcher.max_request_body,
+            login_csp_nonce: launcher.login_csp_nonce,
+            host: launcher.host,
+            port: launcher.port,
+            envs,
+            debug: launcher.debug,
+            uid: launcher.uid,
+            gid: launcher.gid,
+            request_timeout: launcher.request_timeout,
+            download_path: launcher.download_path,
+            mount_bind_download_path: launcher.mount_bind_download_path,
+            listen_options: launcher.listen_options,
+            #[cfg(feature = "http2")]
+            http2: launcher.http2,
+            web_ui_home: launcher.web_ui_home,
+            probe_url: launcher.probe_url,
+            login_page: HTML_LOGIN
+                .replace(
+                    r#"src="/js/"#,
+                    &format!(r#"src="{}/js/"#, launcher.base_path),
+                )
+                .replace(
+                    r#"action="/login""#,
+                    &format!(r#"action="{}/login""#, launcher.base_path),
+                ),
+            base_path: launcher.base_path,
+            allowed_hosts: launcher.allowed_hosts,
+            trusted_proxies: launcher.trusted_proxies,
+            external_url: launcher.external_url,
+            error_page_path: launcher.error_page,
+            error_page: HTML_ERROR.to_owned(),
+            cgi_program: PathBuf::from(env::SYNOPKG_CLI_WEB),
+            cgi_working_dir: launcher.cgi_working_dir,
+            cgi_cache_policy: launcher.cgi_cache_policy,
+            cgi_spawn_retries: launcher.cgi_spawn_retries,
+            cgi_buffer_size: launcher.cgi_buffer_size,
+            tls_cert: launcher.tls_cert,
+            tls_key: launcher.tls_key,
+            min_tls_version: launcher.min_tls_version,
+            tls_cipher_policy: launcher.tls_cipher_policy,
+            drain: launcher.drain,
+            stats: launcher.stats,
+            admin_token: launcher.admin_token,
+            restart_backend: launcher.restart_backend,
+            session_prune_interval: launcher.session_prune_interval,
+            session_store: launcher.session_store,
+            sessions: match launcher.session_store {
+                SessionStoreKind::Memory => Arc::new(MemorySessions::new(
+                    launcher.max_sessions,
+                    launcher.max_sessions_policy,
+                )),
+                SessionStoreKind::File => Arc::new(FileSessions::new(
+                    env::SESSIONS_FILE,
+                    launcher.max_sessions,
+                    launcher.max_sessions_policy,
+                )),
+            },
+            traffic: launcher
+                .session_accounting
+                .then(|| Arc::new(Mutex::new(HashMap::new()))),
+            fallback: match launcher.test_mode {
+                true => Box::new(TestModeFallback),
+                false => Box::new(CgiFallback),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestOpt {
+        #[command(flatten)]
+        config: Config,
+    }
+
+    // A panel configured by launcher command line arguments
+    fn panel(args: &[&str]) -> XunleiPanelServer {
+        let opt = TestOpt::parse_from(std::iter::once("launcher").chain(args.iter().copied()));
+        XunleiPanelServer::from(XunleiLauncher::from((false, opt.config)))
+    }
+
+    fn origin_env(panel: &XunleiPanelServer, request: &Request) -> HashMap<&'static str, String> {
+        panel.cgi_origin_env(request).into_iter().collect()
+    }
+
+    const PROXY: &str = "10.0.0.1:40000";
+    const CLIENT: &str = "192.168.1.9:40000";
+
+    // The request url as handle_cgi sees it, after --base-path is taken off
+    fn cgi_url(base_path: &str, raw_url: &str) -> (String, String) {
+        let request = Request::fake_http("GET", raw_url, vec![], vec![]);
+        let request = match base_path.is_empty() {
+            true => request,
+            false => request.remove_prefix(base_path).unwrap(),
+        };
+        (request.url(), request.raw_query_string().to_owned())
+    }
+
+    #[test]
+    fn cgi_script_mapping_root_base_path() {
+        let (url, _) = cgi_url("", "/webman/3rdparty/pan-xunlei-com/index.cgi/");
+        assert_eq!(
+            cgi_script_mapping(env::SYNOPKG_WEB_UI_HOME, &url),
+            ("/webman/3rdparty/pan-xunlei-com/index.cgi", "/")
+        );
+        // A web UI home at the root matches every path
+        assert_eq!(cgi_script_mapping("/", "/device/now"), ("", "/device/now"));
+    }
+
+    #[test]
+    fn cgi_script_mapping_nested_base_path() {
+        let (url, _) = cgi_url(
+            "/xunlei",
+            "/xunlei/webman/3rdparty/pan-xunlei-com/index.cgi",
+        );
+        assert_eq!(
+            cgi_script_mapping(env::SYNOPKG_WEB_UI_HOME, &url),
+            ("/webman/3rdparty/pan-xunlei-com/index.cgi", "")
+        );
+    }
+
+    #[test]
+    fn cgi_script_mapping_trailing_path() {
+        let (url, _) = cgi_url(
+            "/xunlei",
+            "/xunlei/webman/3rdparty/pan-xunlei-com/index.cgi/device/info/watch",
+        );
+        assert_eq!(
+            cgi_script_mapping(env::SYNOPKG_WEB_UI_HOME, &url),
+            (
+                "/webman/3rdparty/pan-xunlei-com/index.cgi",
+                "/device/info/watch"
+            )
+        );
+        // Only a whole path segment matches the script
+        assert_eq!(
+            cgi_script_mapping(
+                env::SYNOPKG_WEB_UI_HOME,
+                "/webman/3rdparty/pan-xunlei-com/index.cgiX/a"
+            ),
+            ("", "/webman/3rdparty/pan-xunlei-com/index.cgiX/a")
+        );
+    }
+
+    #[test]
+    fn cgi_script_mapping_query_string() {
+        let (url, query) = cgi_url(
+            "",
+            "/webman/3rdparty/pan-xunlei-com/index.cgi/drive/v1/tasks?space=&limit=10",
+        );
+        assert_eq!(
+            cgi_script_mapping(env::SYNOPKG_WEB_UI_HOME, &url),
+            (
+                "/webman/3rdparty/pan-xunlei-com/index.cgi",
+                "/drive/v1/tasks"
+            )
+        );
+        assert_eq!(query, "space=&limit=10");
+    }
+
+    fn forwarded_request(remote_addr: &str) -> Request {
+        Request::fake_http_from(
+            remote_addr.parse().unwrap(),
+            "GET",
+            "/webman/3rdparty/pan-xunlei-com/index.cgi/",
+            vec![
+                ("Host".to_owned(), "10.0.0.2:5055".to_owned()),
+                (
+                    "X-Forwarded-Host".to_owned(),
+                    "nas.example.com:8443, 10.0.0.1".to_owned(),
+                ),
+                ("X-Forwarded-Proto".to_owned(), "https".to_owned()),
+            ],
+            vec![],
+        )
+    }
+
+    #[test]
+    fn cgi_env_forwarded_by_trusted_proxy() {
+        let panel = panel(&["--trust-forwarded-host", "10.0.0.1"]);
+        let envs = origin_env(&panel, &forwarded_request(PROXY));
+        assert_eq!(envs["HTTP_HOST"], "nas.example.com:8443");
+        assert_eq!(envs["SERVER_NAME"], "nas.example.com");
+        assert_eq!(envs["SERVER_PORT"], "8443");
+        assert_eq!(envs["HTTPS"], "on");
+
+        // The default port follows the forwarded scheme
+        let request = Request::fake_http_from(
+            PROXY.parse().unwrap(),
+            "GET",
+            "/",
+            vec![
+                ("X-Forwarded-Host".to_owned(), "[fd00::1]".to_owned()),
+                ("X-Forwarded-Proto".to_owned(), "https".to_owned()),
+            ],
+            vec![],
+        );
+        let envs = origin_env(&panel, &request);
+        assert_eq!(envs["SERVER_NAME"], "[fd00::1]");
+        assert_eq!(envs["SERVER_PORT"], "443");
+    }
+
+    #[test]
+    fn cgi_env_forwarded_by_untrusted_client() {
+        let trusting = panel(&["--trust-forwarded-host", "10.0.0.1"]);
+        let envs = origin_env(&trusting, &forwarded_request(CLIENT));
+        assert!(envs.contains_key("HTTP_HOST").not());
+        assert!(envs.contains_key("SERVER_NAME").not());
+        assert!(envs.contains_key("HTTPS").not());
+        assert_eq!(envs["REQUEST_SCHEME"], "http");
+
+        // Without trusted proxies the forwarded headers are never read
+        let envs = origin_env(&panel(&[]), &forwarded_request(PROXY));
+        assert!(envs.contains_key("HTTP_HOST").not());
+    }
+
+    #[test]
+    fn cgi_scheme_plain() {
+        let request = Request::fake_http_from(CLIENT.parse().unwrap(), "GET", "/", vec![], vec![]);
+        let envs = origin_env(&panel(&[]), &request);
+        assert_eq!(envs["REQUEST_SCHEME"], "http");
+        assert!(envs.contains_key("HTTPS").not());
+    }
+
+    #[test]
+    fn cgi_scheme_tls() {
+        let request = Request::fake_https_from(CLIENT.parse().unwrap(), "GET", "/", vec![], vec![]);
+        let envs = origin_env(&panel(&[]), &request);
+        assert_eq!(envs["REQUEST_SCHEME"], "https");
+        assert_eq!(envs["HTTPS"], "on");
+    }
+
+    #[test]
+    fn cgi_scheme_proxied() {
+        let panel = panel(&["--trust-forwarded-host", "10.0.0.1"]);
+        let proto = |proto: &str| vec![("X-Forwarded-Proto".to_owned(), proto.to_owned())];
+        // A TLS terminating proxy in front of the plain listener
+        let request =
+            Request::fake_http_from(PROXY.parse().unwrap(), "GET", "/", proto("https"), vec![]);
+        let envs = origin_env(&panel, &request);
+        assert_eq!(envs["REQUEST_SCHEME"], "https");
+        assert_eq!(envs["HTTPS"], "on");
+        // A proxy serving plain HTTP in front of the TLS listener
+        let request =
+            Request::fake_https_from(PROXY.parse().unwrap(), "GET", "/", proto("http"), vec![]);
+        let envs = origin_env(&panel, &request);
+        assert_eq!(envs["REQUEST_SCHEME"], "http");
+        assert!(envs.contains_key("HTTPS").not());
+        // The scheme of an untrusted client is ignored
+        let request =
+            Request::fake_http_from(CLIENT.parse().unwrap(), "GET", "/", proto("https"), vec![]);
+        assert_eq!(origin_env(&panel, &request)["REQUEST_SCHEME"], "http");
+    }
+
+    // A file of the test process in the temporary directory, removed by the test
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("xunlei-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn auth_file_credentials() {
+        let hash = hasher_auth_message("secret");
+        let path = temp_file(
+            "auth-valid",
+            &format!(
+                "# users\n\nalice:secret\n  bob:{}{}  \n",
+                AUTH_HASH_PREFIX,
+                hash.to_uppercase()
+            ),
+        );
+        let credentials = load_auth_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let credentials = credentials.unwrap();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials[0].name, "alice");
+        assert_eq!(credentials[0].user, hasher_auth_message("alice"));
+        assert_eq!(credentials[0].password, hash);
+        assert_eq!(credentials[1].name, "bob");
+        assert_eq!(credentials[1].password, hash);
+    }
+
+    #[test]
+    fn auth_file_malformed_line() {
+        for (name, line) in [
+            ("auth-no-colon", "bob"),
+            ("auth-no-user", ":secret"),
+            ("auth-no-password", "bob:"),
+            ("auth-bad-hash", "bob:sha3-512:abcd"),
+        ] {
+            let path = temp_file(name, &format!("alice:secret\n{}\n", line));
+            let result = load_auth_file(&path);
+            std::fs::remove_file(&path).unwrap();
+            let e = result
+                .err()
+                .unwrap_or_else(|| panic!("{:?} was accepted", line));
+            assert!(e.to_string().contains("at line 2"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn auth_file_empty() {
+        for (name, content) in [("auth-empty", ""), ("auth-comments", "# nobody\n\n")] {
+            let path = temp_file(name, content);
+            let result = load_auth_file(&path);
+            std::fs::remove_file(&path).unwrap();
+            let e = result.err().expect("an empty auth file was accepted");
+            assert!(e.to_string().contains("has no credentials"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn auth_file_unreadable_denies() {
+        let path = temp_file("auth-removed", "alice:secret\n");
+        let panel = panel(&["--auth-file", path.to_str().unwrap()]);
+        let login =
+            || panel.authentication(hasher_auth_message("alice"), hasher_auth_message("secret"));
+        assert_eq!(login().as_deref(), Some("alice"));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(login(), None);
+    }
+
+    fn login_request(body: String) -> Request {
+        Request::fake_http(
+            "POST",
+            "/login",
+            vec![(
+                "Content-Type".to_owned(),
+                "application/x-www-form-urlencoded".to_owned(),
+            )],
+            body.into_bytes(),
+        )
+    }
+
+    #[test]
+    fn login_oversized_body() {
+        let panel = panel(&["-U", "admin", "-W", "admin", "--max-login-body", "1024"]);
+        let form = |padding: usize| {
+            format!(
+                "auth_user={}&auth_password={}&pad={}",
+                hasher_auth_message("admin"),
+                hasher_auth_message("admin"),
+                "x".repeat(padding)
+            )
+        };
+        let mut session = None;
+        let response = panel
+            .handle_route(&login_request(form(2048)), &mut session, None)
+            .unwrap();
+        assert_eq!(response.status_code, 413);
+        assert!(session.is_none());
+
+        // The same login within the cap goes through
+        let response = panel
+            .handle_route(&login_request(form(16)), &mut session, None)
+            .unwrap();
+        assert_eq!(response.status_code, 303);
+        assert_eq!(
+            session.and_then(|session| session.user).as_deref(),
+            Some("admin")
+        );
+    }
+
+    // The CGI tests write and run their stub programs one at a time, a stub still open for
+    // writing in a process forked meanwhile fails to exec with ETXTBSY
+    static CGI_STUB: Mutex<()> = Mutex::new(());
+
+    // Answer the request by a CGI program running `script` within the request timeout, with
+    // the response body read
+    fn run_cgi(
+        panel: &mut XunleiPanelServer,
+        name: &str,
+        script: &str,
+        request: &Request,
+    ) -> (Response, String) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _stub = CGI_STUB.lock().unwrap_or_else(|e| e.into_inner());
+        let path = temp_file(name, &format!("#!/bin/sh\n{}\n", script));
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        panel.cgi_program = path.clone();
+        panel.cgi_working_dir = std::env::temp_dir();
+        let deadline = panel
+            .request_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let response = panel.handle_cgi(request, deadline);
+        let response = panel.within_deadline(request, deadline, response);
+        std::fs::remove_file(&path).unwrap();
+        let mut response = response.unwrap();
+        let (mut data, _) = std::mem::replace(&mut response.data, rouille::ResponseBody::empty())
+            .into_reader_and_size();
+        let mut body = String::new();
+        data.read_to_string(&mut body).unwrap();
+        (response, body)
+    }
+
+    #[test]
+    fn cgi_hop_by_hop_headers() {
+        let mut panel = panel(&[]);
+        let request = Request::fake_http("GET", env::SYNOPKG_WEB_UI_HOME, vec![], vec![]);
+        let (response, body) = run_cgi(
+            &mut panel,
+            "cgi-connection",
+            r"printf 'Content-Type: text/plain\r\nConnection: close, X-Hop\r\nKeep-Alive: timeout=5\r\nX-Hop: 1\r\nX-Kept: 1\r\n\r\nhello'",
+            &request,
+        );
+        assert_eq!(response.status_code, 200);
+        assert_eq!(body, "hello");
+        for header in ["Connection", "Keep-Alive", "X-Hop"] {
+            assert!(
+                response
+                    .headers
+                    .iter()
+                    .all(|(k, _)| k.eq_ignore_ascii_case(header).not()),
+                "{} was forwarded: {:?}",
+                header,
+                response.headers
+            );
+        }
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "X-Kept" && v == "1"));
+    }
+
+    #[test]
+    fn cgi_without_output() {
+        let mut plain = panel(&[]);
+        let request = Request::fake_http("GET", env::SYNOPKG_WEB_UI_HOME, vec![], vec![]);
+        for (name, script) in [
+            ("cgi-exit-1", "exit 1"),
+            ("cgi-stderr-only", "echo 'no backend' >&2; exit 1"),
+        ] {
+            let (response, body) = run_cgi(&mut plain, name, script, &request);
+            assert_eq!(response.status_code, 502, "{}", script);
+            assert!(body.contains("502"), "{}", body);
+        }
+        // Also with --request-timeout, where the process group is killed at the deadline
+        let mut timed = panel(&["--request-timeout", "5"]);
+        let (response, _) = run_cgi(&mut timed, "cgi-exit-1-deadline", "exit 1", &request);
+        assert_eq!(response.status_code, 502);
+    }
+
+    #[test]
+    fn cgi_received_bytes() {
+        let mut plain = panel(&[]);
+        // Chunked upload, no Content-Length to go by
+        let request = Request::fake_http(
+            "POST",
+            env::SYNOPKG_WEB_UI_HOME,
+            vec![("Transfer-Encoding".to_owned(), "chunked".to_owned())],
+            vec![b'x'; 5000],
+        );
+        Traffic::take_received();
+        let script = "printf 'Content-Type: text/plain\\r\\n\\r\\n'; wc -c";
+        let (response, body) = run_cgi(&mut plain, "cgi-read-body", script, &request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(body.trim(), "5000");
+        assert_eq!(Traffic::take_received().load(Ordering::Relaxed), 5000);
+    }
+
+    #[test]
+    fn cgi_answers_before_reading_body() {
+        let mut plain = panel(&[]);
+        // Larger than the pipe buffer, the CGI only reads it once its headers are out
+        let request = Request::fake_http(
+            "POST",
+            env::SYNOPKG_WEB_UI_HOME,
+            vec![("Content-Length".to_owned(), (1 << 20).to_string())],
+            vec![b'x'; 1 << 20],
+        );
+        let script = "printf 'Content-Type: text/plain\\r\\n\\r\\n'; wc -c";
+        let (response, body) = run_cgi(&mut plain, "cgi-headers-first", script, &request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(body.trim(), "1048576");
+    }
+
+    // A client that declared a body but sends nothing of it until dropped
+    struct Stalled(mpsc::Receiver<()>);
+
+    impl Read for Stalled {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn cgi_stalled_body_times_out() {
+        let mut timed = panel(&["--request-timeout", "1"]);
+        let request = Request::fake_http(
+            "POST",
+            env::SYNOPKG_WEB_UI_HOME,
+            vec![("Content-Length".to_owned(), "100".to_owned())],
+            vec![],
+        );
+        let (client, stalled) = mpsc::channel();
+        let start = Instant::now();
+        let script = "cat >/dev/null; printf 'Content-Type: text/plain\\r\\n\\r\\n'";
+        let (response, _) = listener::with_body(&request, Box::new(Stalled(stalled)), || {
+            run_cgi(&mut timed, "cgi-stalled-body", script, &request)
+        });
+        assert_eq!(response.status_code, 504);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(client);
+    }
+
+    fn body_of(response: Response) -> String {
+        let mut body = String::new();
+        let (mut data, _) = response.data.into_reader_and_size();
+        data.read_to_string(&mut body).unwrap();
+        body
+    }
+
+    fn header_of<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    #[test]
+    fn login_page_under_base_path() {
+        let panel = panel(&["-U", "admin", "-W", "admin", "--base-path", "/xunlei/"]);
+        let get = |url: &str| {
+            let request = Request::fake_http("GET", url, vec![], vec![]);
+            panel.handle_route(&request, &mut None, None).unwrap()
+        };
+        let login = get("/xunlei/login");
+        assert_eq!(login.status_code, 200);
+        let page = body_of(login);
+        assert!(
+            page.contains(r#"<script src="/xunlei/js/sha3.min.js""#),
+            "{}",
+            page
+        );
+        assert!(page.contains(r#"action="/xunlei/login""#));
+        assert_eq!(get("/xunlei/js/sha3.min.js").status_code, 200);
+    }
+
+    #[test]
+    fn redirects_stay_under_base_path() {
+        let panel = panel(&["-U", "admin", "-W", "admin", "--base-path", "/xunlei"]);
+        let request = Request::fake_http("GET", "/xunlei/", vec![], vec![]);
+        let response = panel.handle_route(&request, &mut None, None).unwrap();
+        assert_eq!(header_of(&response, "Location"), Some("/xunlei/login"));
+
+        let mut session = Some(Session::new(Some("admin".to_owned())));
+        let response = panel.handle_route(&request, &mut session, None).unwrap();
+        assert_eq!(response.status_code, 307);
+        assert_eq!(
+            header_of(&response, "Location"),
+            Some("/xunlei/webman/3rdparty/pan-xunlei-com/index.cgi/")
+        );
+    }
+
+    fn host_status(panel: &XunleiPanelServer, host: Option<&str>) -> u16 {
+        let headers = host
+            .map(|host| vec![("Host".to_owned(), host.to_owned())])
+            .unwrap_or_default();
+        let request = Request::fake_http("GET", "/login", headers, vec![]);
+        panel
+            .handle_route(&request, &mut None, None)
+            .unwrap()
+            .status_code
+    }
+
+    #[test]
+    fn allowed_host() {
+        let panel = panel(&[
+            "-U",
+            "admin",
+            "-W",
+            "admin",
+            "--allowed-host",
+            "nas.lan,nas.lan:8080,[::1]",
+        ]);
+        for host in ["nas.lan", "NAS.lan:5055", "nas.lan:8080", "[::1]:5055"] {
+            assert_eq!(host_status(&panel, Some(host)), 200, "{}", host);
+        }
+    }
+
+    #[test]
+    fn disallowed_host() {
+        let strict = panel(&["-U", "admin", "-W", "admin", "--allowed-host", "nas.lan"]);
+        for host in [
+            Some("evil.example"),
+            Some("nas.lan.evil.example"),
+            Some(""),
+            None,
+        ] {
+            assert_eq!(host_status(&strict, host), 421, "{:?}", host);
+        }
+        // Without --allowed-host any Host is answered
+        let open = panel(&["-U", "admin", "-W", "admin"]);
+        assert_eq!(host_status(&open, Some("evil.example")), 200);
+    }
+
+    #[test]
+    fn login_csp_nonce() {
+        let nonced = panel(&["-U", "admin", "-W", "admin", "--login-csp-nonce"]);
+        let nonce_of = |response: Response| {
+            let csp = header_of(&response, "Content-Security-Policy")
+                .unwrap()
+                .to_owned();
+            let nonce = csp
+                .strip_prefix("script-src 'nonce-")
+                .and_then(|nonce| nonce.strip_suffix('\''))
+                .unwrap_or_else(|| panic!("unexpected policy {}", csp))
+                .to_owned();
+            let page = body_of(response);
+            let scripts = page.matches("<script").count();
+            assert!(scripts > 0);
+            assert_eq!(
+                page.matches(&format!(r#"<script nonce="{}" "#, nonce))
+                    .count(),
+                scripts
+            );
+            nonce
+        };
+        let first = nonce_of(nonced.login_response());
+        let second = nonce_of(nonced.login_response());
+        assert_ne!(first, second);
+
+        // Without the option the page goes out as is
+        let plain = panel(&["-U", "admin", "-W", "admin"]).login_response();
+        assert!(header_of(&plain, "Content-Security-Policy").is_none());
+        assert!(body_of(plain).contains("nonce=").not());
     }
 }
@@ -0,0 +1,289 @@
+use std::cell::RefCell;
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Not;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use nix::sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType};
+
+use crate::error::ErrorKind;
+
+// Longest wait on a client read or write, so that a stalled client can't hold a worker for good
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Request workers of the panel transports, as many as a pooled rouille server starts by default
+pub fn pool_size() -> usize {
+    8 * std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+thread_local! {
+    // Body streamed to the request handled on this thread, keyed by the request address.
+    // Rouille requests can only be built with an owned body
+    static STREAMED_BODY: RefCell<Option<(usize, Box<dyn Read + Send>)>> = const { RefCell::new(None) };
+}
+
+// Run `f` with `body` streamed as the body of `request`, see `take_body`
+pub fn with_body<R>(
+    request: &rouille::Request,
+    body: Box<dyn Read + Send>,
+    f: impl FnOnce() -> R,
+) -> R {
+    let key = request as *const rouille::Request as usize;
+    STREAMED_BODY.with(|streamed| *streamed.borrow_mut() = Some((key, body)));
+    let result = f();
+    STREAMED_BODY.with(|streamed| streamed.borrow_mut().take());
+    result
+}
+
+// The body its transport streams to `request`, which can be taken once
+pub fn take_body(request: &rouille::Request) -> Option<Box<dyn Read + Send>> {
+    let key = request as *const rouille::Request as usize;
+    STREAMED_BODY.with(|streamed| {
+        let mut streamed = streamed.borrow_mut();
+        match streamed.as_ref() {
+            Some((streamed_key, _)) if *streamed_key == key => {
+                streamed.take().map(|(_, body)| body)
+            }
+            _ => None,
+        }
+    })
+}
+
+// Panel listening socket options, the defaults match a plain `TcpListener::bind`
+#[derive(Clone)]
+pub struct ListenOptions {
+    pub backlog: Option<usize>,
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+    pub nodelay: bool,
+}
+
+// Bind a listening socket with the given options, accepted sockets inherit TCP_NODELAY on Linux
+pub fn bind(addr: SocketAddr, options: &ListenOptions) -> anyhow::Result<TcpListener> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket::socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .context("[Listener] Failed to create socket")?;
+    // The listener owns the fd from here on, closing it on any error below
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    socket::setsockopt(fd, sockopt::ReuseAddr, &options.reuse_addr)
+        .context("[Listener] Failed to set SO_REUSEADDR")?;
+    if options.reuse_port {
+        socket::setsockopt(fd, sockopt::ReusePort, &true)
+            .context("[Listener] Failed to set SO_REUSEPORT")?;
+    }
+    if options.nodelay {
+        socket::setsockopt(fd, sockopt::TcpNoDelay, &true)
+            .context("[Listener] Failed to set TCP_NODELAY")?;
+    }
+    match addr {
+        SocketAddr::V4(addr) => socket::bind(fd, &socket::SockaddrIn::from(addr)),
+        SocketAddr::V6(addr) => socket::bind(fd, &socket::SockaddrIn6::from(addr)),
+    }
+    .map_err(|e| {
+        let err = anyhow::Error::from(std::io::Error::from(e))
+            .context(format!("[Listener] Failed to bind {}", addr));
+        match e {
+            nix::errno::Errno::EADDRINUSE => err.context(ErrorKind::PortInUse),
+            _ => err,
+        }
+    })?;
+    // Same default as the standard library
+    socket::listen(fd, options.backlog.unwrap_or(128))
+        .map_err(std::io::Error::from)
+        .context(format!("[Listener] Failed to listen on {}", addr))?;
+    Ok(listener)
+}
+
+// First file descriptor passed by the socket activation protocol
+const LISTEN_FDS_START: RawFd = 3;
+
+// Take over the listening socket passed by systemd socket activation, None when
+// LISTEN_FDS/LISTEN_PID aren't set for this process
+pub fn activated(options: &ListenOptions) -> anyhow::Result<Option<TcpListener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    // The variables must not leak into the backend or CGI processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    if pid.parse::<i32>().ok() != Some(nix::unistd::getpid().as_raw()) {
+        return Ok(None);
+    }
+    match fds.parse::<u32>() {
+        Ok(0) | Err(_) => return Ok(None),
+        Ok(1) => {}
+        Ok(n) => log::warn!(
+            "[Listener] {} sockets passed by socket activation, only the first is used",
+            n
+        ),
+    }
+
+    let fd = LISTEN_FDS_START;
+    if socket::getsockopt(fd, sockopt::AcceptConn)
+        .context("[Listener] Activation fd 3 isn't a socket")?
+        .not()
+    {
+        anyhow::bail!("[Listener] Activation fd 3 isn't a listening socket")
+    }
+    nix::fcntl::fcntl(
+        fd,
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    )
+    .context("[Listener] Failed to set FD_CLOEXEC on the activation socket")?;
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    if options.nodelay {
+        socket::setsockopt(fd, sockopt::TcpNoDelay, &true)
+            .context("[Listener] Failed to set TCP_NODELAY")?;
+    }
+    Ok(Some(listener))
+}
+
+// Largest body the transports pass on for a request, the login form has its own smaller cap,
+// and the longest wait for more of it
+#[derive(Clone)]
+pub struct BodyLimit {
+    max: u64,
+    login: u64,
+    login_path: String,
+    timeout: Duration,
+}
+
+impl BodyLimit {
+    pub fn new(max: u64, login: u64, base_path: &str) -> Self {
+        Self {
+            max,
+            login: login.min(max),
+            login_path: format!("{}/login", base_path),
+            timeout: IDLE_TIMEOUT,
+        }
+    }
+
+    // A stalled body read holds the connection, which the response can't be written to
+    // until the read gives up, so it gives up by the request timeout
+    pub fn with_request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        if let Some(timeout) = timeout {
+            self.timeout = self.timeout.min(timeout);
+        }
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn for_url(&self, url: &str) -> u64 {
+        match url.split('?').next() == Some(self.login_path.as_str()) {
+            true => self.login,
+            false => self.max,
+        }
+    }
+}
+
+// Either the plain HTTP/1.1 server, the TLS one or the hyper one
+pub enum PanelServer<F> {
+    Listener(Server<F>),
+    Tls(crate::tls::Server<F>),
+    #[cfg(feature = "http2")]
+    Http2(crate::http2::Server<F>),
+}
+
+impl<F> PanelServer<F>
+where
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    pub fn run(self) {
+        match self {
+            PanelServer::Listener(server) => server.run(),
+            PanelServer::Tls(server) => server.run(),
+            #[cfg(feature = "http2")]
+            PanelServer::Http2(server) => server.run(),
+        }
+    }
+}
+
+// Serves rouille handlers over HTTP/1.1 on a prepared listener, from a pool of workers
+pub struct Server<F> {
+    listener: TcpListener,
+    body_limit: BodyLimit,
+    pool: threadpool::ThreadPool,
+    handler: Arc<AssertUnwindSafe<F>>,
+}
+
+impl<F> Server<F>
+where
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    pub fn from_listener(listener: TcpListener, body_limit: BodyLimit, handler: F) -> Self {
+        Self {
+            listener,
+            body_limit,
+            pool: threadpool::ThreadPool::new(pool_size()),
+            handler: Arc::new(AssertUnwindSafe(handler)),
+        }
+    }
+
+    pub fn run(self) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::debug!("[Listener] Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let body_limit = self.body_limit.clone();
+            let handler = self.handler.clone();
+            self.pool.execute(move || {
+                if let Err(e) = serve_connection(stream, &body_limit, &**handler) {
+                    log::debug!("[Listener] Connection closed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+fn serve_connection<F>(stream: TcpStream, body_limit: &BodyLimit, handler: &F) -> anyhow::Result<()>
+where
+    F: Fn(&rouille::Request) -> rouille::Response,
+{
+    let remote_addr = stream.peer_addr()?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    crate::http1::serve_requests(stream, remote_addr, false, body_limit, handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_limit_of_login() {
+        let limit = BodyLimit::new(1 << 20, 4096, "");
+        assert_eq!(limit.for_url("/login"), 4096);
+        assert_eq!(limit.for_url("/login?next=/"), 4096);
+        assert_eq!(limit.for_url("/login/x"), 1 << 20);
+        assert_eq!(
+            limit.for_url("/webman/3rdparty/pan-xunlei-com/index.cgi/"),
+            1 << 20
+        );
+
+        // Under a base path only the prefixed login is the login form
+        let limit = BodyLimit::new(1 << 20, 4096, "/xunlei");
+        assert_eq!(limit.for_url("/xunlei/login"), 4096);
+        assert_eq!(limit.for_url("/login"), 1 << 20);
+
+        // The login cap never exceeds the request cap
+        assert_eq!(BodyLimit::new(4096, 8192, "").for_url("/login"), 4096);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Not;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::{env, Running};
+
+const LOG_SOURCES: [(&str, &str); 3] = [
+    ("xunlei", env::LOG_FILE),
+    ("launcher", env::LAUNCH_LOG_FILE),
+    ("install", env::INST_LOG),
+];
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+const TAIL_CHUNK: u64 = 8192;
+const ROTATE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct XunleiLogs {
+    follow: bool,
+    lines: usize,
+}
+
+impl From<(bool, usize)> for XunleiLogs {
+    fn from(value: (bool, usize)) -> Self {
+        Self {
+            follow: value.0,
+            lines: value.1,
+        }
+    }
+}
+
+// Read position of one log file, a partial last line waits for its newline
+struct LogSource {
+    name: &'static str,
+    path: &'static Path,
+    offset: u64,
+    partial: Vec<u8>,
+}
+
+impl LogSource {
+    fn new(name: &'static str, path: &'static str) -> Self {
+        Self {
+            name,
+            path: Path::new(path),
+            offset: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    // Print the last lines of the file and continue from its end
+    fn tail(&mut self, lines: usize, out: &mut impl Write) -> anyhow::Result<()> {
+        let mut file = match File::open(self.path) {
+            Ok(file) => file,
+            Err(_) => return Ok(()),
+        };
+        let len = file.metadata()?.len();
+        let mut start = len;
+        let mut data = Vec::new();
+        // Walk back chunk by chunk until enough lines are buffered
+        while start > 0 && data.iter().filter(|b| **b == b'\n').count() <= lines {
+            start = start.saturating_sub(TAIL_CHUNK);
+            let mut chunk = vec![0; (len - start) as usize - data.len()];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut chunk)?;
+            chunk.append(&mut data);
+            data = chunk;
+        }
+        self.offset = len;
+
+        let text = String::from_utf8_lossy(&data);
+        let all = text.lines().collect::<Vec<_>>();
+        for line in &all[all.len().saturating_sub(lines)..] {
+            writeln!(out, "[{}] {}", self.name, line)?;
+        }
+        Ok(())
+    }
+
+    // Print the lines appended since the last read, starting over when the file was rotated or truncated
+    fn poll(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let mut file = match File::open(self.path) {
+            Ok(file) => file,
+            Err(_) => return Ok(()),
+        };
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            self.offset = 0;
+            self.partial.clear();
+        }
+        if len == self.offset {
+            return Ok(());
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let read = file
+            .take(len - self.offset)
+            .read_to_end(&mut self.partial)?;
+        self.offset += read as u64;
+
+        if let Some(end) = self.partial.iter().rposition(|b| *b == b'\n') {
+            let rest = self.partial.split_off(end + 1);
+            for line in String::from_utf8_lossy(&self.partial).lines() {
+                writeln!(out, "[{}] {}", self.name, line)?;
+            }
+            self.partial = rest;
+        }
+        Ok(())
+    }
+}
+
+impl Running for XunleiLogs {
+    fn run(self) -> anyhow::Result<()> {
+        let mut sources = LOG_SOURCES
+            .iter()
+            .map(|(name, path)| LogSource::new(name, path))
+            .collect::<Vec<_>>();
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+
+        for source in sources.iter_mut() {
+            if source.path.exists() {
+                source.tail(self.lines, &mut out)?;
+            } else {
+                log::warn!("[XunleiLogs] {} doesn't exist yet", source.path.display());
+            }
+        }
+        out.flush()?;
+
+        if self.follow.not() {
+            return Ok(());
+        }
+        loop {
+            std::thread::sleep(FOLLOW_INTERVAL);
+            for source in sources.iter_mut() {
+                source.poll(&mut out)?;
+            }
+            out.flush()?;
+        }
+    }
+}
+
+// Size and time based rotation of a log file that another process keeps open.
+// The file can't be renamed from under its writer, so it is copied into a gzip
+// segment and truncated in place, lines written in between are lost
+#[derive(Clone)]
+pub struct LogRotation {
+    path: PathBuf,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    keep: u32,
+}
+
+impl LogRotation {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        max_size: Option<u64>,
+        interval: Option<Duration>,
+        keep: u32,
+    ) -> Self {
+        Self {
+            path: path.into(),
+            max_size,
+            interval,
+            keep,
+        }
+    }
+
+    // Check the file periodically, rotation errors are logged and retried at the next check
+    pub fn run(self) {
+        if self.max_size.is_none() && self.interval.is_none() {
+            log::warn!(
+                "[LogRotation] Neither a size nor an interval is set, {} is not rotated",
+                self.path.display()
+            );
+            return;
+        }
+        let mut last_rotation = Instant::now();
+        loop {
+            std::thread::sleep(ROTATE_CHECK_INTERVAL);
+            let len = match std::fs::metadata(&self.path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            let oversized = self.max_size.is_some_and(|max_size| len > max_size);
+            let due = self
+                .interval
+                .is_some_and(|interval| last_rotation.elapsed() >= interval);
+            if len == 0 || (oversized || due).not() {
+                continue;
+            }
+            match self.rotate() {
+                Ok(_) => {
+                    last_rotation = Instant::now();
+                    log::info!(
+                        "[LogRotation] Rotated {} ({} bytes)",
+                        self.path.display(),
+                        len
+                    );
+                }
+                Err(e) => log::warn!("[LogRotation] {:#}", e),
+            }
+        }
+    }
+
+    fn segment(&self, index: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}.gz", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> anyhow::Result<()> {
+        // Compress into a temporary file first, so that a failure leaves the segments untouched
+        let tmp = self.segment(1).with_extension("gz.tmp");
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        let len = file.metadata()?.len();
+        let mut compress = || -> anyhow::Result<()> {
+            let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::default());
+            std::io::copy(&mut (&mut file).take(len), &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            Ok(())
+        };
+        if let Err(e) = compress() {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e.context(format!("Failed to compress {}", self.path.display())));
+        }
+
+        let _ = std::fs::remove_file(self.segment(self.keep));
+        for index in (1..self.keep).rev() {
+            let segment = self.segment(index);
+            if segment.exists() {
+                std::fs::rename(&segment, self.segment(index + 1))
+                    .with_context(|| format!("Failed to rename {}", segment.display()))?;
+            }
+        }
+        std::fs::rename(&tmp, self.segment(1))
+            .with_context(|| format!("Failed to rename {}", tmp.display()))?;
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .and_then(|file| file.set_len(0))
+            .with_context(|| format!("Failed to truncate {}", self.path.display()))?;
+        Ok(())
+    }
+}
@@ -1,16 +1,36 @@
+#[cfg(feature = "launcher")]
+pub mod cgroup;
 #[cfg(feature = "daemon")]
 pub mod daemon;
 pub mod env;
+pub mod error;
+#[cfg(feature = "launcher")]
+pub mod http1;
+#[cfg(feature = "http2")]
+pub mod http2;
 #[cfg(feature = "launcher")]
 pub mod launcher;
 #[cfg(all(target_os = "linux", target_env = "musl"))]
 pub mod libc_asset;
+#[cfg(feature = "launcher")]
+pub mod listener;
+pub mod logs;
+#[cfg(feature = "launcher")]
+pub mod mount;
+#[cfg(feature = "launcher")]
+pub mod session;
+pub mod status;
+#[cfg(feature = "launcher")]
+pub mod tls;
 pub mod util;
-#[cfg(feature = "daemon")]
+#[cfg(any(feature = "daemon", feature = "launcher"))]
 pub mod xunlei_asset;
 
-use clap::{Args, Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, Command, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::collections::HashSet;
 use std::io::Write;
+use std::ops::Not;
 use std::path::PathBuf;
 
 pub trait Running {
@@ -24,6 +44,9 @@ struct Opt {
     /// Enable debug
     #[clap(long, global = true, env = "XUNLEI_DEBUG")]
     debug: bool,
+    /// Timezone of log timestamps: local, utc or an IANA name like Asia/Shanghai
+    #[clap(long, global = true, env = "XUNLEI_LOG_TIMEZONE", default_value = "local", value_parser = parser_log_timezone)]
+    log_timezone: LogTimezone,
 
     #[clap(subcommand)]
     commands: Commands,
@@ -33,7 +56,13 @@ struct Opt {
 pub enum Commands {
     #[cfg(feature = "daemon")]
     /// Install xunlei
-    Install(Config),
+    Install {
+        #[command(flatten)]
+        config: Box<Config>,
+        /// Prompt for the port, UID/GID, directories and credentials, and save the answers for reuse
+        #[clap(long)]
+        first_run_wizard: bool,
+    },
     #[cfg(feature = "daemon")]
     /// Uninstall xunlei
     Uninstall {
@@ -41,9 +70,58 @@ pub enum Commands {
         #[clap(short, long)]
         clear: bool,
     },
+    #[cfg(feature = "daemon")]
+    /// Upgrade xunlei, keeping the config directory
+    Upgrade {
+        /// Install the assets of a local xunlei spk package
+        #[clap(short, long)]
+        asset_file: Option<PathBuf>,
+        /// Xunlei UID permission, the owner of the installed files by default
+        #[clap(long, env = "XUNLEI_UID")]
+        uid: Option<u32>,
+        /// Xunlei GID permission, the group of the installed files by default
+        #[clap(long, env = "XUNLEI_GID")]
+        gid: Option<u32>,
+    },
     #[cfg(feature = "launcher")]
     /// Launcher xunlei
-    Launcher(Config),
+    Launcher(Box<Config>),
+    /// Show the xunlei logs together, each line prefixed with its source
+    Logs {
+        /// Keep printing lines as they are appended
+        #[clap(short, long)]
+        follow: bool,
+        /// Number of last lines to show from each log
+        #[clap(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+    },
+    /// Show whether the xunlei backend is running and the state of its runtime files
+    Status {
+        /// Print the result as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    #[cfg(feature = "launcher")]
+    /// Check the capabilities and directories the launcher needs with the given options
+    Doctor {
+        #[command(flatten)]
+        config: Box<Config>,
+        /// Print the result as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    #[cfg(feature = "launcher")]
+    /// Print the environment the launcher starts the backend with
+    Env {
+        #[command(flatten)]
+        config: Box<Config>,
+        /// Print the result as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    #[cfg(feature = "launcher")]
+    /// Read a password from stdin and print its hash for an --auth-file line
+    GenerateHash,
 }
 
 #[derive(Args)]
@@ -54,11 +132,23 @@ pub struct Config {
     /// Xunlei authentication password
     #[arg(short = 'W', long, env = "XUNLEI_AUTH_PASSWORD")]
     auth_password: Option<String>,
+    /// File of USER:PASSWORD lines accepted by the panel, a malformed or empty file aborts startup
+    #[arg(long, env = "XUNLEI_AUTH_FILE", conflicts_with_all = ["auth_user", "auth_password"])]
+    auth_file: Option<PathBuf>,
+    /// Largest POST /login body in bytes, bigger bodies are rejected before they are parsed
+    #[arg(long, env = "XUNLEI_MAX_LOGIN_BODY", default_value_t = 4096, value_parser = clap::value_parser!(u32).range(512..))]
+    max_login_body: u32,
+    /// Largest request body in bytes the panel accepts, bigger bodies are answered 413 before they are read whole
+    #[arg(long, env = "XUNLEI_MAX_REQUEST_BODY", default_value_t = 64 << 20, value_parser = clap::value_parser!(u64).range(4096..))]
+    max_request_body: u64,
+    /// Send the login page with a Content-Security-Policy allowing only its own scripts, by a per-response nonce
+    #[arg(long, env = "XUNLEI_LOGIN_CSP_NONCE")]
+    login_csp_nonce: bool,
     /// Xunlei Listen host
-    #[clap(short = 'H', long, env = "XUNLEI_HOST", default_value = "0.0.0.0", value_parser = parser_host)]
-    host: std::net::IpAddr,
+    #[clap(short = 'H', long, env = "XUNLEI_HOST", default_value = env::DEFAULT_HOST, value_parser = parser_host)]
+    host: ListenHost,
     /// Xunlei Listen port
-    #[clap(short = 'P', long, env = "XUNLEI_PORT", default_value = "5055", value_parser = parser_port_in_range)]
+    #[clap(short = 'P', long, env = "XUNLEI_PORT", default_value = env::DEFAULT_PORT, value_parser = parser_port_in_range)]
     port: u16,
     /// Xunlei UID permission
     #[clap(long, env = "XUNLEI_UID")]
@@ -66,56 +156,506 @@ pub struct Config {
     /// Xunlei GID permission
     #[clap(long, env = "XUNLEI_GID")]
     gid: Option<u32>,
+    /// Run the xunlei processes as root when --uid/--gid are unset and the launcher is root
+    #[arg(long, env = "XUNLEI_ALLOW_ROOT")]
+    allow_root: bool,
     /// Xunlei config directory
-    #[clap(short, long, default_value = env::DEFAULT_CONFIG_PATH)]
+    #[clap(short, long, env = "XUNLEI_CONFIG_PATH", default_value = env::DEFAULT_CONFIG_PATH)]
     config_path: PathBuf,
     /// Xunlei download directory
-    #[clap(short, long, default_value = env::DEFAULT_DOWNLOAD_PATH)]
+    #[clap(short, long, env = "XUNLEI_DOWNLOAD_PATH", default_value = env::DEFAULT_DOWNLOAD_PATH)]
     download_path: PathBuf,
     /// Xunlei mount bind download directory
-    #[clap(short, long, default_value = env::DEFAULT_BIND_DOWNLOAD_PATH)]
+    #[clap(short, long, env = "XUNLEI_MOUNT_BIND_DOWNLOAD_PATH", default_value = env::DEFAULT_BIND_DOWNLOAD_PATH)]
     mount_bind_download_path: PathBuf,
+    /// Panel request timeout in seconds, for the response to start, each wait for more of the request body and each read of a CGI response body
+    #[clap(long, env = "XUNLEI_REQUEST_TIMEOUT", value_parser = clap::value_parser!(u64).range(1..))]
+    request_timeout: Option<u64>,
+    /// Backend cgroup v2 memory limit, e.g. 512M or max
+    #[clap(long, env = "XUNLEI_CGROUP_MEMORY_MAX", value_parser = parser_cgroup_memory_max)]
+    cgroup_memory_max: Option<String>,
+    /// Backend cgroup v2 CPU limit as "$QUOTA [$PERIOD]" in microseconds, e.g. "50000 100000"
+    #[clap(long, env = "XUNLEI_CGROUP_CPU_MAX", value_parser = parser_cgroup_cpu_max)]
+    cgroup_cpu_max: Option<String>,
+    /// Extra KEY=VALUE environment of the backend and CGI, $VAR and ${VAR} expand from their environment
+    #[clap(long, env = "XUNLEI_BACKEND_ENV", value_delimiter = '\n', value_parser = parser_backend_env)]
+    backend_env: Vec<(String, String)>,
+    /// Fail when a --backend-env value references an undefined variable, instead of expanding it empty
+    #[clap(long, env = "XUNLEI_BACKEND_ENV_STRICT")]
+    backend_env_strict: bool,
+    /// KEY=VALUE file applied last to the backend and CGI environment, it can replace the package values
+    #[clap(long, env = "XUNLEI_ENV_OVERRIDE_FILE")]
+    env_override_file: Option<PathBuf>,
+    /// Working directory of the backend and its hooks, for non-standard package layouts
+    #[clap(long, env = "XUNLEI_BACKEND_WORKING_DIR", default_value = env::SYNOPKG_PKGDEST)]
+    backend_working_dir: PathBuf,
+    /// Experimental: run the backend chrooted into a root of bind mounts, skipped without CAP_SYS_CHROOT and CAP_SYS_ADMIN
+    #[clap(long, env = "XUNLEI_CHROOT")]
+    chroot: bool,
+    /// CGI path requested through the panel bridge each time the backend socket comes up, in order, before it counts as up
+    #[clap(long, env = "XUNLEI_POST_START", value_delimiter = '\n', value_parser = parser_url_path)]
+    post_start: Vec<String>,
+    /// Stop the launcher when a --post-start request fails, instead of logging it
+    #[clap(long, env = "XUNLEI_POST_START_STRICT")]
+    post_start_strict: bool,
+    /// Shell command run each time the backend socket comes up, with the backend environment, UID and GID
+    #[clap(long, env = "XUNLEI_ON_BACKEND_READY")]
+    on_backend_ready: Option<String>,
+    /// Seconds to wait for the backend socket after each start before the backend counts as failed, 0 waits forever
+    #[clap(long, env = "XUNLEI_READY_TIMEOUT", default_value_t = 120)]
+    ready_timeout: u64,
+    /// Milliseconds between checks for the backend socket while waiting for it
+    #[clap(long, env = "XUNLEI_READY_INTERVAL", default_value_t = 500, value_parser = clap::value_parser!(u64).range(10..))]
+    ready_interval: u64,
+    /// Path POSTed to the backend socket on shutdown for it to pause its downloads, before it is signaled and waited for
+    #[clap(long, env = "XUNLEI_DRAIN_ON_SHUTDOWN", value_parser = parser_url_path)]
+    drain_on_shutdown: Option<String>,
+    /// Seconds to wait for --drain-on-shutdown to be answered
+    #[clap(long, env = "XUNLEI_DRAIN_TIMEOUT", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    drain_timeout: u64,
+    /// Shell command run once the backend has stopped, before the download directory is unmounted
+    #[clap(long, env = "XUNLEI_ON_SHUTDOWN")]
+    on_shutdown: Option<String>,
+    /// CPU niceness of the backend, from -20 (highest priority) to 19 (lowest)
+    #[clap(long, env = "XUNLEI_BACKEND_NICE", allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    backend_nice: Option<i32>,
+    /// IO scheduling class of the backend
+    #[clap(long, env = "XUNLEI_BACKEND_IOPRIO_CLASS", value_enum)]
+    backend_ioprio_class: Option<IoprioClass>,
+    /// IO priority of the backend within its class, from 0 (highest) to 7 (lowest), unused by idle
+    #[clap(long, env = "XUNLEI_BACKEND_IOPRIO_LEVEL", default_value_t = 4, value_parser = clap::value_parser!(u8).range(0..=7))]
+    backend_ioprio_level: u8,
+    /// CPUs the backend is pinned to, as a list like 0-2,4
+    #[clap(long, env = "XUNLEI_BACKEND_CPU_AFFINITY", value_parser = parser_cpu_list)]
+    backend_cpu_affinity: Option<CpuList>,
+    /// Rotate the backend log by copy and truncate, keeping gzip compressed segments next to it
+    #[clap(long, env = "XUNLEI_ROTATE_BACKEND_LOG")]
+    rotate_backend_log: bool,
+    /// Rotate the backend log once it is larger than this many bytes, 0 disables the size trigger
+    #[clap(long, env = "XUNLEI_ROTATE_BACKEND_LOG_SIZE", default_value_t = 10 * 1024 * 1024)]
+    rotate_backend_log_size: u64,
+    /// Rotate the backend log every this many seconds, 0 disables the time trigger
+    #[clap(long, env = "XUNLEI_ROTATE_BACKEND_LOG_INTERVAL", default_value_t = 0)]
+    rotate_backend_log_interval: u64,
+    /// Rotated backend log segments to keep
+    #[clap(long, env = "XUNLEI_ROTATE_BACKEND_LOG_KEEP", default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    rotate_backend_log_keep: u32,
+    /// Serve the panel with hyper, which also speaks HTTP/2 (ALPN with TLS, prior knowledge without)
+    #[cfg(feature = "http2")]
+    #[clap(long, env = "XUNLEI_HTTP2")]
+    http2: bool,
+    /// Panel listen backlog [default: 128]
+    #[clap(long, env = "XUNLEI_LISTEN_BACKLOG", value_parser = clap::value_parser!(u32).range(1..))]
+    listen_backlog: Option<u32>,
+    /// Set SO_REUSEADDR on the panel listening socket
+    #[clap(long, env = "XUNLEI_REUSE_ADDR", default_value_t = true, action = clap::ArgAction::Set)]
+    reuse_addr: bool,
+    /// Set SO_REUSEPORT on the panel listening socket, so that other processes can serve the same port
+    #[clap(long, env = "XUNLEI_REUSE_PORT")]
+    reuse_port: bool,
+    /// Panel processes sharing the port with SO_REUSEPORT, only the first one supervises the backend
+    #[clap(long, env = "XUNLEI_WORKERS", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=64), conflicts_with = "backend_only")]
+    workers: u32,
+    /// Set TCP_NODELAY on panel connections
+    #[clap(long, env = "XUNLEI_TCP_NODELAY")]
+    tcp_nodelay: bool,
+    /// Backend restarts before the failure policy applies
+    #[clap(long, env = "XUNLEI_BACKEND_RESTART_MAX", default_value_t = 0)]
+    backend_restart_max: u32,
+    /// Only count backend restarts within this many seconds towards --backend-restart-max
+    #[clap(long, env = "XUNLEI_BACKEND_RESTART_WINDOW", value_parser = clap::value_parser!(u64).range(1..))]
+    backend_restart_window: Option<u64>,
+    /// What to do once the backend keeps failing
+    #[clap(long, env = "XUNLEI_ON_BACKEND_FAILURE", value_enum, default_value_t = OnBackendFailure::Hold)]
+    on_backend_failure: OnBackendFailure,
+    /// Leave the bind download directory mounted when the launcher exits
+    #[clap(long, env = "XUNLEI_NO_MOUNT_UMOUNT_ON_EXIT")]
+    no_mount_umount_on_exit: bool,
+    /// Seconds to wait for the download directory to appear before the bind mount, for late network or auto mounts
+    #[clap(long, env = "XUNLEI_MOUNT_WAIT", default_value_t = 5)]
+    mount_wait: u64,
+    /// Propagation of the bind download directory mount
+    #[clap(long, env = "XUNLEI_MOUNT_PROPAGATION", value_enum, default_value_t = MountPropagation::Private)]
+    mount_propagation: MountPropagation,
+    /// Xunlei web UI home the panel redirects to, for non-standard package builds
+    #[clap(long, env = "XUNLEI_WEB_UI_HOME", default_value = env::SYNOPKG_WEB_UI_HOME, value_parser = parser_web_ui_home)]
+    web_ui_home: String,
+    /// CGI path requested by /readyz, a 2xx or 3xx answer means the backend is ready
+    #[clap(long, env = "XUNLEI_PROBE_URL", default_value = env::DEFAULT_PROBE_URL, value_parser = parser_url_path)]
+    probe_url: String,
+    /// Path prefix the panel is also served under, like /xunlei, the login page links carry it
+    #[clap(long, env = "XUNLEI_BASE_PATH", value_parser = parser_base_path)]
+    base_path: Option<String>,
+    /// Host headers the panel answers, as HOST or HOST:PORT, comma separated. Others get 421, all are answered when unset
+    #[clap(long, env = "XUNLEI_ALLOWED_HOST", value_delimiter = ',')]
+    allowed_host: Vec<String>,
+    /// Proxy addresses trusted to set X-Forwarded-Host/X-Forwarded-Proto, comma separated
+    #[clap(long, env = "XUNLEI_TRUST_FORWARDED_HOST", value_delimiter = ',')]
+    trust_forwarded_host: Vec<std::net::IpAddr>,
+    /// Externally reachable panel base URL like https://nas.example.com/xunlei, used for links behind a reverse proxy
+    #[clap(long, env = "XUNLEI_EXTERNAL_URL", value_parser = parser_external_url)]
+    external_url: Option<String>,
+    /// Token required in the X-Admin-Token header of /admin routes, which are disabled without it
+    #[clap(long, env = "XUNLEI_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    /// Working directory of the CGI processes, for non-standard package layouts
+    #[clap(long, env = "XUNLEI_CGI_WORKING_DIR", default_value = env::SYNOPKG_PKGDEST)]
+    cgi_working_dir: PathBuf,
+    /// CGI stdout read buffer in bytes, allocated once per in-flight request
+    #[clap(long, env = "XUNLEI_CGI_BUFFER_SIZE", default_value_t = 64 * 1024, value_parser = clap::value_parser!(u32).range(1024..))]
+    cgi_buffer_size: u32,
+    /// Caching of CGI responses, no-store-html keeps browsers from serving a stale UI after an upgrade
+    #[clap(long, env = "XUNLEI_CGI_CACHE_POLICY", value_enum, default_value_t = CgiCachePolicy::NoStoreHtml)]
+    cgi_cache_policy: CgiCachePolicy,
+    /// Retries of a CGI spawn failing with EAGAIN/ENOMEM before responding 503
+    #[clap(long, env = "XUNLEI_CGI_SPAWN_RETRIES", default_value_t = 2)]
+    cgi_spawn_retries: u32,
+    /// HTML page served for 5xx responses, {{status}}, {{reason}} and {{request_id}} are substituted
+    #[clap(long, env = "XUNLEI_ERROR_PAGE")]
+    error_page: Option<PathBuf>,
+    /// Panel HTTPS PEM certificate chain as [HOST=]CERT, HOST picks it by SNI hostname
+    #[clap(long, env = "XUNLEI_TLS_CERT", value_delimiter = ',', value_parser = parser_tls_cert)]
+    tls_cert: Vec<(Option<String>, PathBuf)>,
+    /// PEM private key of each --tls-cert, in the same order
+    #[clap(long, env = "XUNLEI_TLS_KEY", value_delimiter = ',')]
+    tls_key: Vec<PathBuf>,
+    /// Oldest TLS version the panel accepts, 1.2 or 1.3
+    #[clap(long, env = "XUNLEI_MIN_TLS_VERSION", default_value = "1.2", value_parser = parser_tls_version)]
+    min_tls_version: TlsVersion,
+    /// Cipher suites and key exchange groups the panel offers over TLS
+    #[clap(long, env = "XUNLEI_TLS_CIPHER_POLICY", value_enum, default_value_t = TlsCipherPolicy::Standard)]
+    tls_cipher_policy: TlsCipherPolicy,
+    /// Seconds between sweeps removing expired panel sessions
+    #[clap(long, env = "XUNLEI_SESSION_PRUNE_INTERVAL", default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    session_prune_interval: u64,
+    /// Where the panel keeps its sessions, file shares them between processes and keeps them across restarts. --workers always uses file
+    #[clap(long, env = "XUNLEI_SESSION_STORE", value_enum, default_value_t = SessionStoreKind::Memory)]
+    session_store: SessionStoreKind,
+    /// Panel sessions kept at most, protecting the session table from exhaustion
+    #[clap(long, env = "XUNLEI_MAX_SESSIONS", default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..))]
+    max_sessions: u32,
+    /// What a new session does once --max-sessions is reached
+    #[clap(long, env = "XUNLEI_MAX_SESSIONS_POLICY", value_enum, default_value_t = MaxSessionsPolicy::Evict)]
+    max_sessions_policy: MaxSessionsPolicy,
+    /// Seconds between INFO heartbeat lines with uptime, sessions, backend PID and request count, 0 disables them
+    #[clap(long, env = "XUNLEI_HEARTBEAT_INTERVAL", default_value_t = 0)]
+    heartbeat_interval: u64,
+    /// Count the panel bytes received and sent per logged in user, reported by /admin/sessions
+    #[clap(long, env = "XUNLEI_SESSION_ACCOUNTING")]
+    session_accounting: bool,
+    /// Seconds to let in-flight panel requests finish on shutdown
+    #[clap(long, env = "XUNLEI_SHUTDOWN_TIMEOUT", default_value_t = 10)]
+    shutdown_timeout: u64,
+    /// Only run the panel, the backend runs elsewhere
+    #[clap(long, env = "XUNLEI_PANEL_ONLY", conflicts_with = "backend_only")]
+    panel_only: bool,
+    /// Only run the backend, the panel runs elsewhere
+    #[clap(long, env = "XUNLEI_BACKEND_ONLY")]
+    backend_only: bool,
+    /// Stub the mounts and run a placeholder instead of the backend, logging what would have happened
+    #[clap(long, env = "XUNLEI_TEST_MODE", hide = true)]
+    test_mode: bool,
+    /// Print the effective configuration and where each value came from, then exit
+    #[clap(long)]
+    dump_config: bool,
+    /// Print the compiled-in default paths, host and port as KEY=VALUE, then exit
+    #[clap(long)]
+    print_default_paths: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountPropagation {
+    /// Mount events don't propagate in either direction
+    Private,
+    /// Mount events propagate to and from the peer group
+    Shared,
+    /// Mount events only propagate from the master into the mount
+    Slave,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgiCachePolicy {
+    /// Replace the cache headers of HTML responses with no-store, other content stays cacheable
+    NoStoreHtml,
+    /// Replace the cache headers of every response with no-store
+    NoStore,
+    /// Forward the cache headers of the CGI as they are
+    Passthrough,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    V1_2,
+    V1_3,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsCipherPolicy {
+    /// Every suite and group rustls implements, all of them forward secret AEAD
+    Standard,
+    /// Only 256-bit strength suites, with the X25519 and P-384 groups
+    Strict,
 }
 
-fn main() -> anyhow::Result<()> {
-    let opt = Opt::parse();
-    init_log(opt.debug);
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionStoreKind {
+    /// In the panel process
+    Memory,
+    /// In the package var directory, next to the other runtime files
+    File,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaxSessionsPolicy {
+    /// Drop the least recently active session to make room
+    Evict,
+    /// Answer new logins with 503 until sessions expire
+    Reject,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoprioClass {
+    /// Served first, starves the other classes when busy
+    Realtime,
+    /// The kernel default, shared by priority level
+    BestEffort,
+    /// Only served when no other process needs the disk
+    Idle,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnBackendFailure {
+    /// Exit the launcher, so that an external supervisor restarts the whole stack
+    Exit,
+    /// Re-extract the embedded assets and retry once, then exit, needs the embed feature
+    Reextract,
+    /// Keep the panel running without the backend
+    Hold,
+}
+
+fn main() {
+    let mut command = Opt::command();
+    let matches = command.get_matches_mut();
+    let opt = Opt::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        if let Ok(Some(true)) = sub_matches.try_get_one::<bool>("print_default_paths") {
+            print_default_paths();
+            return;
+        }
+        if let Ok(Some(true)) = sub_matches.try_get_one::<bool>("dump_config") {
+            let mut seen = HashSet::new();
+            dump_config(&command, &matches, &mut seen);
+            if let Some(subcommand) = command.find_subcommand(name) {
+                dump_config(subcommand, sub_matches, &mut seen);
+            }
+            return;
+        }
+    }
+    init_log(opt.debug, opt.log_timezone);
+    install_panic_hook();
+    if let Err(err) = execute(opt) {
+        log::error!("{:#}", err);
+        std::process::exit(error::exit_code(&err));
+    }
+}
+
+fn execute(opt: Opt) -> anyhow::Result<()> {
     match opt.commands {
         #[cfg(feature = "daemon")]
-        Commands::Install(config) => {
-            daemon::XunleiInstall::from((opt.debug, config)).run()?;
+        Commands::Install {
+            mut config,
+            first_run_wizard,
+        } => {
+            if first_run_wizard {
+                daemon::first_run_wizard(&mut config)?;
+            }
+            daemon::XunleiInstall::from((opt.debug, *config)).run()?;
         }
         #[cfg(feature = "daemon")]
         Commands::Uninstall { clear } => {
             daemon::XunleiUninstall::from(clear).run()?;
         }
+        #[cfg(feature = "daemon")]
+        Commands::Upgrade {
+            asset_file,
+            uid,
+            gid,
+        } => {
+            daemon::XunleiUpgrade::from((asset_file, uid, gid)).run()?;
+        }
         #[cfg(feature = "launcher")]
         Commands::Launcher(config) => {
-            launcher::XunleiLauncher::from((opt.debug, config)).run()?;
+            launcher::XunleiLauncher::from((opt.debug, *config)).run()?;
+        }
+        Commands::Logs { follow, lines } => {
+            logs::XunleiLogs::from((follow, lines)).run()?;
+        }
+        Commands::Status { json } => {
+            status::XunleiStatus::from(json).run()?;
+        }
+        #[cfg(feature = "launcher")]
+        Commands::Doctor { config, json } => {
+            let launcher = launcher::XunleiLauncher::from((opt.debug, *config));
+            launcher::XunleiDoctor::from((launcher, json)).run()?;
+        }
+        #[cfg(feature = "launcher")]
+        Commands::Env { config, json } => {
+            let launcher = launcher::XunleiLauncher::from((opt.debug, *config));
+            launcher::XunleiEnv::from((launcher, json)).run()?;
+        }
+        #[cfg(feature = "launcher")]
+        Commands::GenerateHash => {
+            launcher::XunleiGenerateHash.run()?;
         }
     }
     Ok(())
 }
 
-fn init_log(debug: bool) {
+// Log panics through the logger with a backtrace, instead of the default stderr message
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        log::error!(
+            "Thread '{}' {}\n{}",
+            thread.name().unwrap_or("<unnamed>"),
+            info,
+            std::backtrace::Backtrace::force_capture()
+        );
+    }));
+}
+
+fn print_default_paths() {
+    for (key, value) in [
+        ("CONFIG_PATH", env::DEFAULT_CONFIG_PATH),
+        ("DOWNLOAD_PATH", env::DEFAULT_DOWNLOAD_PATH),
+        ("MOUNT_BIND_DOWNLOAD_PATH", env::DEFAULT_BIND_DOWNLOAD_PATH),
+        ("HOST", env::DEFAULT_HOST),
+        ("PORT", env::DEFAULT_PORT),
+    ] {
+        println!("{}={}", key, value);
+    }
+}
+
+// Options whose values --dump-config masks
+const SECRET_ARGS: [&str; 2] = ["auth_password", "admin_token"];
+
+// Print each option with its effective value and provenance: default, env:VAR or cli
+fn dump_config<'a>(command: &'a Command, matches: &ArgMatches, seen: &mut HashSet<&'a str>) {
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(
+            id,
+            "help" | "version" | "dump_config" | "print_default_paths"
+        ) || seen.insert(id).not()
+        {
+            continue;
+        }
+        let value = match matches.get_raw(id) {
+            Some(_) if SECRET_ARGS.contains(&id) => String::from("******"),
+            Some(values) => values
+                .map(|v| v.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(","),
+            None => String::from("<unset>"),
+        };
+        let source = match matches.value_source(id) {
+            Some(ValueSource::DefaultValue) => String::from("default"),
+            Some(ValueSource::EnvVariable) => format!(
+                "env:{}",
+                arg.get_env().unwrap_or_default().to_string_lossy()
+            ),
+            Some(ValueSource::CommandLine) => String::from("cli"),
+            _ => String::from("unset"),
+        };
+        println!("{} = {} ({})", arg.get_long().unwrap_or(id), value, source);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum LogTimezone {
+    Local,
+    Utc,
+    Named(chrono_tz::Tz),
+}
+
+fn init_log(debug: bool, timezone: LogTimezone) {
     match debug {
         true => std::env::set_var("RUST_LOG", "DEBUG"),
         false => std::env::set_var("RUST_LOG", "INFO"),
     };
     env_logger::builder()
-        .format(|buf, record| {
+        .format(move |buf, record| {
             writeln!(
                 buf,
                 "{} {}: {}",
                 record.level(),
                 //Format like you want to: <-----------------
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                match timezone {
+                    LogTimezone::Local => chrono::Local::now().format(LOG_TIME_FORMAT),
+                    LogTimezone::Utc => chrono::Utc::now().format(LOG_TIME_FORMAT),
+                    LogTimezone::Named(tz) => chrono::Utc::now()
+                        .with_timezone(&tz)
+                        .format(LOG_TIME_FORMAT),
+                },
                 record.args()
             )
         })
         .init();
 }
 
+const LOG_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Log timezone parser
+fn parser_log_timezone(s: &str) -> anyhow::Result<LogTimezone> {
+    match s.to_ascii_lowercase().as_str() {
+        "local" => Ok(LogTimezone::Local),
+        "utc" => Ok(LogTimezone::Utc),
+        _ => s
+            .parse::<chrono_tz::Tz>()
+            .map(LogTimezone::Named)
+            .map_err(|_| anyhow::anyhow!(format!("`{}` isn't local, utc or an IANA timezone", s))),
+    }
+}
+
+// Sorted CPU numbers of a cpulist
+#[derive(Clone)]
+pub struct CpuList(Vec<usize>);
+
+impl CpuList {
+    pub fn cpus(&self) -> &[usize] {
+        &self.0
+    }
+}
+
+// cpulist parser, comma separated CPU numbers and inclusive ranges
+fn parser_cpu_list(s: &str) -> anyhow::Result<CpuList> {
+    let cpu = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .map_err(|_| anyhow::anyhow!(format!("`{}` isn't a CPU number", s)))
+    };
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (cpu(first)?, cpu(last)?);
+                if first > last {
+                    anyhow::bail!(format!("`{}` isn't an ascending CPU range", part))
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(cpu(part)?),
+        }
+    }
+    let max = nix::sched::CpuSet::count();
+    if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= max) {
+        anyhow::bail!(format!("CPU {} is above the supported {}", cpu, max - 1))
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(CpuList(cpus))
+}
+
 const PORT_RANGE: std::ops::RangeInclusive<usize> = 1024..=65535;
 
 // port range parser
@@ -133,10 +673,270 @@ fn parser_port_in_range(s: &str) -> anyhow::Result<u16> {
     ))
 }
 
-// address parser
-fn parser_host(s: &str) -> anyhow::Result<std::net::IpAddr> {
-    let addr = s
-        .parse::<std::net::IpAddr>()
-        .map_err(|_| anyhow::anyhow!(format!("`{}` isn't a ip address", s)))?;
-    Ok(addr)
+// Listen host, a hostname is kept as given and only resolved when the panel binds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenHost {
+    Ip(std::net::IpAddr),
+    Name(String),
+}
+
+impl ListenHost {
+    // The same address on every run, IPv4 first, the other resolved addresses are logged and dropped
+    pub fn resolve(&self) -> anyhow::Result<std::net::IpAddr> {
+        let name = match self {
+            ListenHost::Ip(addr) => return Ok(*addr),
+            ListenHost::Name(name) => name,
+        };
+        let addrs = std::net::ToSocketAddrs::to_socket_addrs(&(name.as_str(), 0))
+            .map_err(|e| anyhow::anyhow!(format!("`{}` doesn't resolve: {}", name, e)))?
+            .map(|addr| addr.ip())
+            .collect::<Vec<_>>();
+        let (addr, dropped) = pick_address(addrs)
+            .ok_or_else(|| anyhow::anyhow!(format!("`{}` doesn't resolve to any address", name)))?;
+        if dropped.is_empty().not() {
+            log::warn!(
+                "[Listener] `{}` resolves to several addresses, listening on {} only, dropped {}",
+                name,
+                addr,
+                dropped
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(addr)
+    }
+}
+
+impl std::fmt::Display for ListenHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenHost::Ip(addr) => write!(f, "{}", addr),
+            ListenHost::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// The lowest IPv4 address, or the lowest IPv6 one without IPv4, and the rest
+fn pick_address(
+    mut addrs: Vec<std::net::IpAddr>,
+) -> Option<(std::net::IpAddr, Vec<std::net::IpAddr>)> {
+    addrs.sort_by_key(|addr| (addr.is_ipv6(), *addr));
+    addrs.dedup();
+    match addrs.is_empty() {
+        true => None,
+        false => {
+            let addr = addrs.remove(0);
+            Some((addr, addrs))
+        }
+    }
+}
+
+// Listen host parser, literal IPs are taken as is, anything else must be a valid hostname
+fn parser_host(s: &str) -> anyhow::Result<ListenHost> {
+    if let Ok(addr) = s.parse::<std::net::IpAddr>() {
+        return Ok(ListenHost::Ip(addr));
+    }
+    let label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && label.starts_with('-').not()
+            && label.ends_with('-').not()
+    };
+    let name = s.strip_suffix('.').unwrap_or(s);
+    if name.len() > 253 || name.split('.').all(label).not() {
+        anyhow::bail!(format!("`{}` isn't a ip address or hostname", s))
+    }
+    Ok(ListenHost::Name(s.to_owned()))
+}
+
+// Web UI home parser, an absolute URL path always ending with a slash
+fn parser_web_ui_home(s: &str) -> anyhow::Result<String> {
+    if s.starts_with('/').not() {
+        anyhow::bail!(format!(
+            "`{}` isn't an absolute path like {}",
+            s,
+            env::SYNOPKG_WEB_UI_HOME
+        ))
+    }
+    match s.ends_with('/') {
+        true => Ok(s.to_owned()),
+        false => Ok(format!("{}/", s)),
+    }
+}
+
+// TLS version parser, the versions before 1.2 are refused as too weak
+fn parser_tls_version(s: &str) -> anyhow::Result<TlsVersion> {
+    match s {
+        "1.2" => Ok(TlsVersion::V1_2),
+        "1.3" => Ok(TlsVersion::V1_3),
+        "1.0" | "1.1" => anyhow::bail!(format!("TLS {} is too weak, use 1.2 or 1.3", s)),
+        _ => anyhow::bail!(format!("`{}` isn't a TLS version, use 1.2 or 1.3", s)),
+    }
+}
+
+// URL path parser, an absolute URL path with an optional query string
+fn parser_url_path(s: &str) -> anyhow::Result<String> {
+    match s.starts_with('/') {
+        true => Ok(s.to_owned()),
+        false => anyhow::bail!(format!(
+            "`{}` isn't an absolute path like {}",
+            s,
+            env::DEFAULT_PROBE_URL
+        )),
+    }
+}
+
+// External URL parser, an http(s) URL kept without its trailing slash
+fn parser_external_url(s: &str) -> anyhow::Result<String> {
+    let rest = s
+        .strip_prefix("https://")
+        .or_else(|| s.strip_prefix("http://"))
+        .ok_or_else(|| anyhow::anyhow!("`{}` isn't an http:// or https:// URL", s))?;
+    if rest.split('/').next().unwrap_or_default().is_empty() {
+        anyhow::bail!("`{}` has no host", s)
+    }
+    Ok(s.trim_end_matches('/').to_owned())
+}
+
+// Base path parser, an absolute path kept without its trailing slash
+fn parser_base_path(s: &str) -> anyhow::Result<String> {
+    if s.starts_with('/').not() {
+        anyhow::bail!(format!("`{}` isn't an absolute path like /xunlei", s))
+    }
+    Ok(s.trim_end_matches('/').to_owned())
+}
+
+// Backend environment parser, KEY=VALUE with the value expanded later
+fn parser_backend_env(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if key.is_empty().not() => Ok((key.to_owned(), value.to_owned())),
+        _ => anyhow::bail!(format!("`{}` isn't KEY=VALUE", s)),
+    }
+}
+
+// TLS certificate parser, an optional SNI hostname followed by the certificate path
+fn parser_tls_cert(s: &str) -> anyhow::Result<(Option<String>, PathBuf)> {
+    match s.split_once('=') {
+        Some(("", _)) => {
+            anyhow::bail!(format!("`{}` has an empty hostname", s))
+        }
+        Some((host, cert)) => Ok((Some(host.to_ascii_lowercase()), PathBuf::from(cert))),
+        None => Ok((None, PathBuf::from(s))),
+    }
+}
+
+// cgroup memory.max parser, bytes with an optional K/M/G/T suffix
+fn parser_cgroup_memory_max(s: &str) -> anyhow::Result<String> {
+    if s == "max" {
+        return Ok(s.to_owned());
+    }
+    let digits = s.strip_suffix(['K', 'M', 'G', 'T']).unwrap_or(s);
+    if digits.is_empty() || digits.chars().any(|c| !c.is_ascii_digit()) {
+        anyhow::bail!(format!("`{}` isn't a memory size like 512M or max", s))
+    }
+    Ok(s.to_owned())
+}
+
+// cgroup cpu.max parser
+fn parser_cgroup_cpu_max(s: &str) -> anyhow::Result<String> {
+    let mut parts = s.split_whitespace();
+    let quota = parts.next().unwrap_or_default();
+    let period = parts.next();
+    let valid = (quota == "max" || quota.parse::<u64>().is_ok_and(|q| q > 0))
+        && period.is_none_or(|p| p.parse::<u64>().is_ok_and(|p| p > 0))
+        && parts.next().is_none();
+    if !valid {
+        anyhow::bail!(format!(
+            "`{}` isn't a cpu limit like \"50000 100000\" or max",
+            s
+        ))
+    }
+    Ok(s.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn parser_host_ipv4() {
+        assert_eq!(
+            parser_host("192.168.1.2").unwrap(),
+            ListenHost::Ip(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)))
+        );
+        assert_eq!(
+            parser_host("0.0.0.0").unwrap(),
+            ListenHost::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        );
+    }
+
+    #[test]
+    fn parser_host_ipv6() {
+        assert_eq!(
+            parser_host("::").unwrap(),
+            ListenHost::Ip(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        );
+        assert_eq!(
+            parser_host("fe80::1").unwrap(),
+            ListenHost::Ip(IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)))
+        );
+    }
+
+    #[test]
+    fn parser_host_localhost() {
+        let host = parser_host("localhost").unwrap();
+        assert_eq!(host, ListenHost::Name(String::from("localhost")));
+        assert_eq!(host.to_string(), "localhost");
+        assert!(host.resolve().unwrap().is_loopback());
+    }
+
+    #[test]
+    fn pick_address_prefers_ipv4() {
+        let v4 = |d| IpAddr::V4(Ipv4Addr::new(192, 168, 1, d));
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(
+            pick_address(vec![v6, v4(3), v4(2), v4(3)]),
+            Some((v4(2), vec![v4(3), v6]))
+        );
+        assert_eq!(pick_address(vec![v6]), Some((v6, Vec::new())));
+        assert_eq!(pick_address(Vec::new()), None);
+    }
+
+    #[test]
+    fn parser_host_invalid() {
+        assert!(parser_host("").is_err());
+        assert!(parser_host("not a host").is_err());
+        assert!(parser_host("-nas.local").is_err());
+        assert!(parser_host("nas..local").is_err());
+    }
+
+    #[test]
+    fn parser_url_path_absolute() {
+        assert_eq!(
+            parser_url_path("/webman/3rdparty/pan-xunlei-com/index.cgi/device/now?x=1").unwrap(),
+            "/webman/3rdparty/pan-xunlei-com/index.cgi/device/now?x=1"
+        );
+        assert!(parser_url_path("device/now").is_err());
+        assert!(parser_url_path("").is_err());
+    }
+
+    #[test]
+    fn secret_args_exist() {
+        let command = Opt::command();
+        for id in SECRET_ARGS {
+            assert!(
+                command
+                    .get_subcommands()
+                    .flat_map(Command::get_arguments)
+                    .any(|arg| arg.get_id() == id),
+                "{}",
+                id
+            );
+        }
+    }
 }
@@ -0,0 +1,412 @@
+use std::ops::Not;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::mount::MsFlags;
+
+use crate::error::ErrorKind;
+use crate::MountPropagation;
+
+const SOURCE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// The mount syscalls used by `BindMount`, kept behind a trait so that they can be replaced
+pub trait Mounter {
+    fn mount(&self, source: Option<&Path>, target: &Path, flags: MsFlags) -> nix::Result<()>;
+    fn umount(&self, target: &Path) -> nix::Result<()>;
+
+    // Unmount once the target is no longer busy
+    fn detach(&self, target: &Path) -> nix::Result<()> {
+        self.umount(target)
+    }
+}
+
+// Performs the real mount(2)/umount(2) calls
+pub struct SystemMounter;
+
+impl Mounter for SystemMounter {
+    fn mount(&self, source: Option<&Path>, target: &Path, flags: MsFlags) -> nix::Result<()> {
+        nix::mount::mount(
+            source,
+            target,
+            <Option<&'static [u8]>>::None,
+            flags,
+            <Option<&'static [u8]>>::None,
+        )
+    }
+
+    fn umount(&self, target: &Path) -> nix::Result<()> {
+        nix::mount::umount(target)
+    }
+
+    fn detach(&self, target: &Path) -> nix::Result<()> {
+        nix::mount::umount2(target, nix::mount::MntFlags::MNT_DETACH)
+    }
+}
+
+// Logs the mounts instead of doing them, for --test-mode
+pub struct RecordingMounter;
+
+impl Mounter for RecordingMounter {
+    fn mount(&self, source: Option<&Path>, target: &Path, flags: MsFlags) -> nix::Result<()> {
+        log::info!(
+            "[TestMode] mount {} on {} ({:?})",
+            source
+                .map(|source| source.display().to_string())
+                .unwrap_or_default(),
+            target.display(),
+            flags
+        );
+        Ok(())
+    }
+
+    fn umount(&self, target: &Path) -> nix::Result<()> {
+        log::info!("[TestMode] umount {}", target.display());
+        Ok(())
+    }
+}
+
+impl<M: Mounter + ?Sized> Mounter for Box<M> {
+    fn mount(&self, source: Option<&Path>, target: &Path, flags: MsFlags) -> nix::Result<()> {
+        (**self).mount(source, target, flags)
+    }
+
+    fn umount(&self, target: &Path) -> nix::Result<()> {
+        (**self).umount(target)
+    }
+
+    fn detach(&self, target: &Path) -> nix::Result<()> {
+        (**self).detach(target)
+    }
+}
+
+// The mounter of the launcher, recording only with --test-mode
+pub fn mounter(test_mode: bool) -> Box<dyn Mounter + Send + Sync> {
+    match test_mode {
+        true => Box::new(RecordingMounter),
+        false => Box::new(SystemMounter),
+    }
+}
+
+// A bind mount of `source` on `target`
+pub struct BindMount<M = SystemMounter> {
+    source: PathBuf,
+    target: PathBuf,
+    mounter: M,
+}
+
+impl BindMount {
+    pub fn new(source: impl Into<PathBuf>, target: impl Into<PathBuf>) -> Self {
+        Self::with_mounter(source, target, SystemMounter)
+    }
+}
+
+impl<M: Mounter> BindMount<M> {
+    pub fn with_mounter(
+        source: impl Into<PathBuf>,
+        target: impl Into<PathBuf>,
+        mounter: M,
+    ) -> Self {
+        Self {
+            source: source.into(),
+            target: target.into(),
+            mounter,
+        }
+    }
+
+    // Poll until the source is a directory, it may be a network or auto mount that isn't ready yet
+    pub fn wait_source(&self, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut logged = false;
+        while self.source.is_dir().not() {
+            if Instant::now() >= deadline {
+                return Err(anyhow::anyhow!(
+                    "[BindMount] {} is not a directory after waiting {}s",
+                    self.source.display(),
+                    timeout.as_secs()
+                )
+                .context(ErrorKind::Permission));
+            }
+            if logged.not() {
+                log::info!(
+                    "[BindMount] Waiting up to {}s for {}",
+                    timeout.as_secs(),
+                    self.source.display()
+                );
+                logged = true;
+            }
+            std::thread::sleep(SOURCE_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    // Bind the source on the target, replacing a mount left over by a previous run
+    pub fn mount(&self) -> anyhow::Result<()> {
+        let _ = self.mounter.umount(&self.target);
+        match self
+            .mounter
+            .mount(Some(&self.source), &self.target, MsFlags::MS_BIND)
+        {
+            Ok(_) => {
+                log::info!(
+                    "[BindMount] Mount {} to {} succeeded",
+                    self.source.display(),
+                    self.target.display()
+                );
+                Ok(())
+            }
+            Err(e) => Err(anyhow::anyhow!(
+                "[BindMount] Mount {} to {} failed: {}",
+                self.source.display(),
+                self.target.display(),
+                e
+            )
+            .context(ErrorKind::Permission)),
+        }
+    }
+
+    // Set the propagation type of the mount, the mount is undone when that fails
+    pub fn set_propagation(&self, propagation: MountPropagation) -> anyhow::Result<()> {
+        let flags = match propagation {
+            MountPropagation::Private => MsFlags::MS_PRIVATE,
+            MountPropagation::Shared => MsFlags::MS_SHARED,
+            MountPropagation::Slave => MsFlags::MS_SLAVE,
+        };
+        if let Err(e) = self.mounter.mount(None, &self.target, flags) {
+            let _ = self.mounter.umount(&self.target);
+            return Err(anyhow::anyhow!(
+                "[BindMount] Set {:?} propagation on {} failed: {}",
+                propagation,
+                self.target.display(),
+                e
+            )
+            .context(ErrorKind::Permission));
+        }
+        log::info!("[BindMount] Mount propagation: {:?}", propagation);
+        Ok(())
+    }
+
+    pub fn umount(&self) -> anyhow::Result<()> {
+        self.mounter.umount(&self.target).map_err(|e| {
+            anyhow::anyhow!(
+                "[BindMount] Unmount {} failed: {}",
+                self.target.display(),
+                e
+            )
+        })?;
+        log::info!("[BindMount] Unmount {} succeeded", self.target.display());
+        Ok(())
+    }
+
+    pub fn detach(&self) -> anyhow::Result<()> {
+        self.mounter.detach(&self.target).map_err(|e| {
+            anyhow::anyhow!("[BindMount] Detach {} failed: {}", self.target.display(), e)
+        })?;
+        log::info!("[BindMount] Detach {} succeeded", self.target.display());
+        Ok(())
+    }
+}
+
+// A root assembled from bind mounts of host paths, each at the same place under it
+pub struct Chroot {
+    root: PathBuf,
+    mounts: Vec<BindMount>,
+}
+
+impl Chroot {
+    // Missing paths are skipped, the mounts done so far are undone when one fails
+    pub fn assemble(root: impl Into<PathBuf>, paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut chroot = Self {
+            root: root.into(),
+            mounts: Vec::new(),
+        };
+        if let Err(e) = chroot.bind_all(paths) {
+            chroot.umount();
+            return Err(e);
+        }
+        log::info!(
+            "[Chroot] Assembled {} from {} bind mounts",
+            chroot.root.display(),
+            chroot.mounts.len()
+        );
+        Ok(chroot)
+    }
+
+    fn bind_all(&mut self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        // Parents first, so that nested paths are mounted on top of them
+        let mut paths = paths.to_vec();
+        paths.sort_by_key(|path| path.components().count());
+        paths.dedup();
+        for path in paths {
+            if path.exists().not() {
+                continue;
+            }
+            let target = self.root.join(path.strip_prefix("/").unwrap_or(&path));
+            if path.is_dir() {
+                std::fs::create_dir_all(&target)?;
+            } else if target.exists().not() {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::File::create(&target)?;
+            }
+            let mount = BindMount::new(&path, &target);
+            mount.mount()?;
+            self.mounts.push(mount);
+        }
+        let tmp = self.root.join("tmp");
+        std::fs::create_dir_all(&tmp)?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o1777))?;
+        Ok(())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Detached, the backend may still be exiting with files open inside
+    pub fn umount(&mut self) {
+        while let Some(mount) = self.mounts.pop() {
+            if let Err(e) = mount.detach() {
+                log::warn!("{}", e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Records the calls, failing the ones for the flags or the umounts it is told to
+    #[derive(Clone, Default)]
+    struct MockMounter {
+        calls: Rc<RefCell<Vec<String>>>,
+        fail_flags: Option<MsFlags>,
+        fail_umount: bool,
+    }
+
+    impl MockMounter {
+        fn calls(&self) -> Vec<String> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl Mounter for MockMounter {
+        fn mount(&self, source: Option<&Path>, target: &Path, flags: MsFlags) -> nix::Result<()> {
+            self.calls.borrow_mut().push(format!(
+                "mount {} {} {:?}",
+                source.map(|s| s.display().to_string()).unwrap_or_default(),
+                target.display(),
+                flags
+            ));
+            match self.fail_flags == Some(flags) {
+                true => Err(nix::errno::Errno::EPERM),
+                false => Ok(()),
+            }
+        }
+
+        fn umount(&self, target: &Path) -> nix::Result<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("umount {}", target.display()));
+            match self.fail_umount {
+                true => Err(nix::errno::Errno::EINVAL),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn bind_mount(mounter: &MockMounter) -> BindMount<MockMounter> {
+        BindMount::with_mounter("/downloads", "/mnt/xunlei", mounter.clone())
+    }
+
+    #[test]
+    fn mount_replaces_leftover() {
+        let mounter = MockMounter {
+            // Nothing is mounted on the target yet
+            fail_umount: true,
+            ..Default::default()
+        };
+        bind_mount(&mounter).mount().unwrap();
+        assert_eq!(
+            mounter.calls(),
+            ["umount /mnt/xunlei", "mount /downloads /mnt/xunlei MS_BIND"]
+        );
+    }
+
+    #[test]
+    fn mount_failure() {
+        let mounter = MockMounter {
+            fail_flags: Some(MsFlags::MS_BIND),
+            ..Default::default()
+        };
+        let e = bind_mount(&mounter).mount().unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::Permission)
+        ));
+        assert!(format!("{:#}", e).contains("Mount /downloads to /mnt/xunlei failed"));
+    }
+
+    #[test]
+    fn set_propagation() {
+        let mounter = MockMounter::default();
+        bind_mount(&mounter)
+            .set_propagation(MountPropagation::Slave)
+            .unwrap();
+        assert_eq!(mounter.calls(), ["mount  /mnt/xunlei MS_SLAVE"]);
+    }
+
+    #[test]
+    fn set_propagation_failure_undoes_mount() {
+        let mounter = MockMounter {
+            fail_flags: Some(MsFlags::MS_SHARED),
+            ..Default::default()
+        };
+        assert!(bind_mount(&mounter)
+            .set_propagation(MountPropagation::Shared)
+            .is_err());
+        assert_eq!(
+            mounter.calls(),
+            ["mount  /mnt/xunlei MS_SHARED", "umount /mnt/xunlei"]
+        );
+    }
+
+    #[test]
+    fn umount_and_detach() {
+        let mounter = MockMounter::default();
+        let mount = bind_mount(&mounter);
+        mount.umount().unwrap();
+        // Without its own detach a mounter unmounts
+        mount.detach().unwrap();
+        assert_eq!(
+            mounter.calls(),
+            ["umount /mnt/xunlei", "umount /mnt/xunlei"]
+        );
+
+        let mounter = MockMounter {
+            fail_umount: true,
+            ..Default::default()
+        };
+        let e = bind_mount(&mounter).umount().unwrap_err();
+        assert!(e.to_string().contains("Unmount /mnt/xunlei failed"));
+    }
+
+    #[test]
+    fn wait_source_timeout() {
+        let mounter = MockMounter::default();
+        let mount = BindMount::with_mounter("/nonexistent/xunlei", "/mnt/xunlei", mounter.clone());
+        let e = mount.wait_source(Duration::ZERO).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ErrorKind>(),
+            Some(ErrorKind::Permission)
+        ));
+        assert!(mounter.calls().is_empty());
+        BindMount::with_mounter("/", "/mnt/xunlei", mounter)
+            .wait_source(Duration::ZERO)
+            .unwrap();
+    }
+}
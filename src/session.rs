@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, Write};
+use std::ops::Not;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::MaxSessionsPolicy;
+
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(3600);
+
+// This struct contains the data that we store on the server about each client.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub last_active: Instant,
+    // The logged in user, None without authentication
+    pub user: Option<String>,
+}
+
+impl Session {
+    pub fn new(user: Option<String>) -> Self {
+        Self {
+            last_active: Instant::now(),
+            user,
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.last_active.elapsed() > SESSION_LIFETIME
+    }
+}
+
+// Where the panel keeps its sessions, selected by --session-store
+pub trait SessionStore: Send + Sync {
+    // The session of the ID unless it expired
+    fn get(&self, id: &str) -> Option<Session>;
+    // Insert or refresh the session, false when the --max-sessions policy refused it
+    fn keep(&self, id: &str, session: Session) -> bool;
+    fn remove(&self, id: &str);
+    // Drop the expired sessions, returning how many went and how many are left
+    fn prune(&self) -> (usize, usize);
+    fn count(&self) -> usize;
+    fn list(&self) -> Vec<Session>;
+}
+
+// Sessions of this process only, lost on restart
+pub struct MemorySessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    max: usize,
+    policy: MaxSessionsPolicy,
+}
+
+impl MemorySessions {
+    pub fn new(max: usize, policy: MaxSessionsPolicy) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            max,
+            policy,
+        }
+    }
+
+    // None when the session was refused, otherwise whether it is new
+    fn insert(&self, id: &str, session: Session) -> Option<bool> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let new = sessions.contains_key(id).not();
+        if new && sessions.len() >= self.max {
+            match self.policy {
+                MaxSessionsPolicy::Reject => {
+                    log::debug!(
+                        "[Sessions] Session limit {} reached, new session not kept",
+                        self.max
+                    );
+                    return None;
+                }
+                MaxSessionsPolicy::Evict => evict_oldest(&mut sessions),
+            }
+        }
+        sessions.insert(id.to_owned(), session);
+        Some(new)
+    }
+
+    fn retain(&self, f: impl FnMut(&String, &mut Session) -> bool) {
+        self.sessions.lock().unwrap().retain(f)
+    }
+
+    fn snapshot(&self) -> HashMap<String, Session> {
+        self.sessions.lock().unwrap().clone()
+    }
+}
+
+impl SessionStore for MemorySessions {
+    fn get(&self, id: &str) -> Option<Session> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|session| session.expired().not())
+            .cloned()
+    }
+
+    fn keep(&self, id: &str, session: Session) -> bool {
+        self.insert(id, session).is_some()
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    fn prune(&self) -> (usize, usize) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.expired().not());
+        (before - sessions.len(), sessions.len())
+    }
+
+    fn count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    fn list(&self) -> Vec<Session> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.expired().not())
+            .cloned()
+            .collect()
+    }
+}
+
+// Make room for a session by dropping the least recently active one
+fn evict_oldest(sessions: &mut HashMap<String, Session>) {
+    let oldest = sessions
+        .iter()
+        .min_by_key(|(_, session)| session.last_active)
+        .map(|(id, _)| id.clone());
+    if let Some(session) = oldest.and_then(|id| sessions.remove(&id)) {
+        log::debug!(
+            "[Sessions] Session limit reached, evicted a session of {} idle for {}s",
+            session.user.as_deref().unwrap_or("no user"),
+            session.last_active.elapsed().as_secs()
+        );
+    }
+}
+
+// Logged in sessions also kept in a file, so that a login is valid on every panel
+// process and survives a restart. Each process caches the sessions in memory, writes
+// a session to the file when it first sees it and its activity at each prune, so the
+// others may see an activity up to that much older
+pub struct FileSessions {
+    local: MemorySessions,
+    path: PathBuf,
+    // The file as last read, keyed by its modification time and length
+    stored: Mutex<(Option<FileVersion>, HashMap<String, StoredSession>)>,
+}
+
+type FileVersion = (SystemTime, u64);
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct StoredSession {
+    user: Option<String>,
+    // Unix seconds
+    last_active: u64,
+}
+
+impl FileSessions {
+    pub fn new(path: impl Into<PathBuf>, max: usize, policy: MaxSessionsPolicy) -> Self {
+        Self {
+            local: MemorySessions::new(max, policy),
+            path: path.into(),
+            stored: Mutex::new((None, HashMap::new())),
+        }
+    }
+
+    fn unix(instant: Instant) -> u64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        now.saturating_sub(instant.elapsed()).as_secs()
+    }
+
+    fn instant(unix: u64) -> Option<Instant> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Instant::now().checked_sub(now.saturating_sub(Duration::from_secs(unix)))
+    }
+
+    // Run `f` on the stored sessions under an exclusive lock, writing them back when it returns true
+    fn update(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, StoredSession>) -> bool,
+    ) -> anyhow::Result<()> {
+        use nix::fcntl::{flock, FlockArg};
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&self.path)?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let mut sessions = serde_json::from_str(&content).unwrap_or_default();
+        if f(&mut sessions) {
+            let content = serde_json::to_vec(&sessions)?;
+            file.set_len(0)?;
+            file.seek(std::io::SeekFrom::Start(0))?;
+            file.write_all(&content)?;
+        }
+        // The lock is released with the file
+        Ok(())
+    }
+
+    // Re-read the file when it changed since the last read, dropping the logged in
+    // sessions that other processes removed from it
+    fn refresh(&self) {
+        let version = std::fs::metadata(&self.path)
+            .ok()
+            .and_then(|meta| Some((meta.modified().ok()?, meta.len())));
+        if self.stored.lock().unwrap().0 == version {
+            return;
+        }
+        // A removed file took its sessions along
+        let sessions = match version.map(|_| self.read()) {
+            None => HashMap::new(),
+            Some(Ok(sessions)) => sessions,
+            Some(Err(e)) => {
+                log::warn!("[Sessions] Failed to read {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        self.local
+            .retain(|id, session| session.user.is_none() || sessions.contains_key(id));
+        *self.stored.lock().unwrap() = (version, sessions);
+    }
+
+    // The stored sessions under a shared lock, so that a write in progress isn't seen
+    fn read(&self) -> anyhow::Result<HashMap<String, StoredSession>> {
+        use nix::fcntl::{flock, FlockArg};
+        use std::os::fd::AsRawFd;
+
+        let mut file = std::fs::File::open(&self.path)?;
+        flock(file.as_raw_fd(), FlockArg::LockShared)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        Ok(serde_json::from_str(&content).unwrap_or_default())
+    }
+
+    fn load(&self, id: &str) -> Option<Session> {
+        let found = self.stored.lock().unwrap().1.get(id).cloned()?;
+        Some(Session {
+            last_active: Self::instant(found.last_active)?,
+            user: found.user,
+        })
+        .filter(|session| session.expired().not())
+    }
+
+    fn store(&self, id: &str, session: &Session) {
+        let stored = StoredSession {
+            user: session.user.clone(),
+            last_active: Self::unix(session.last_active),
+        };
+        if let Err(e) = self.update(|sessions| {
+            sessions.insert(id.to_owned(), stored);
+            true
+        }) {
+            log::warn!("[Sessions] Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+
+    // Merge the activity of this process and drop the expired sessions of all of them
+    fn sync(&self, local: &HashMap<String, Session>) {
+        let result = self.update(|sessions| {
+            for (id, session) in local {
+                let last_active = Self::unix(session.last_active);
+                if let Some(stored) = sessions.get_mut(id) {
+                    stored.last_active = stored.last_active.max(last_active);
+                }
+            }
+            let now = Self::unix(Instant::now());
+            sessions.retain(|_, stored| {
+                now.saturating_sub(stored.last_active) <= SESSION_LIFETIME.as_secs()
+            });
+            true
+        });
+        if let Err(e) = result {
+            log::warn!("[Sessions] Failed to sync {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl SessionStore for FileSessions {
+    fn get(&self, id: &str) -> Option<Session> {
+        // The login or the logout may have happened on another process
+        self.refresh();
+        self.local.get(id).or_else(|| self.load(id))
+    }
+
+    fn keep(&self, id: &str, session: Session) -> bool {
+        // Sessions without a user are recreated on every request, they stay local
+        let stored = session.user.is_some().then(|| session.clone());
+        match self.local.insert(id, session) {
+            None => false,
+            Some(new) => {
+                if let (true, Some(session)) = (new, stored) {
+                    self.store(id, &session);
+                }
+                true
+            }
+        }
+    }
+
+    fn remove(&self, id: &str) {
+        self.local.remove(id);
+        if let Err(e) = self.update(|sessions| sessions.remove(id).is_some()) {
+            log::warn!("[Sessions] Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn prune(&self) -> (usize, usize) {
+        let pruned = self.local.prune();
+        self.sync(&self.local.snapshot());
+        pruned
+    }
+
+    fn count(&self) -> usize {
+        self.local.count()
+    }
+
+    fn list(&self) -> Vec<Session> {
+        self.local.list()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(path: &PathBuf) -> FileSessions {
+        FileSessions::new(path, 16, MaxSessionsPolicy::Reject)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("xunlei-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn logout_reaches_other_workers() {
+        let path = temp_path("sessions-logout");
+        let (first, second) = (worker(&path), worker(&path));
+        assert!(first.keep("session-a", Session::new(Some("bob".into()))));
+        let session = second
+            .get("session-a")
+            .expect("login seen by the other worker");
+        assert!(second.keep("session-a", session));
+
+        first.remove("session-a");
+        assert!(first.get("session-a").is_none());
+        assert!(second.get("session-a").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unchanged_file_not_reread() {
+        let path = temp_path("sessions-reread");
+        let (first, second) = (worker(&path), worker(&path));
+        assert!(first.keep("session-a", Session::new(Some("bob".into()))));
+        assert!(second.get("session-a").is_some());
+
+        // Same length and modification time, the cached read still stands
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replace("session-a", "session-b")).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        assert!(second.get("session-b").is_none());
+        assert!(second.get("session-a").is_some());
+
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert!(second.get("session-b").is_some());
+        assert!(second.get("session-a").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prune_keeps_sessions() {
+        let path = temp_path("sessions-prune");
+        let first = worker(&path);
+        assert!(first.keep("session-a", Session::new(Some("bob".into()))));
+        assert!(first.keep("session-b", Session::new(None)));
+        assert_eq!(first.prune(), (0, 2));
+        assert!(worker(&path).get("session-a").is_some());
+        assert!(worker(&path).get("session-b").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
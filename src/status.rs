@@ -0,0 +1,81 @@
+use std::fmt;
+use std::path::Path;
+
+use nix::unistd::Pid;
+
+use crate::{env, util, Running};
+
+pub struct XunleiStatus {
+    json: bool,
+}
+
+impl From<bool> for XunleiStatus {
+    fn from(json: bool) -> Self {
+        Self { json }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Status {
+    running: bool,
+    pid: Option<i32>,
+    socket_exists: bool,
+    checks: Vec<util::Check>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) if self.running => writeln!(f, "xunlei is running, PID {}", pid)?,
+            _ => writeln!(f, "xunlei is not running")?,
+        }
+        for check in &self.checks {
+            writeln!(f, "{}", check)?;
+        }
+        Ok(())
+    }
+}
+
+// The backend writes its PID file once started, a stale one names a dead process
+fn backend_pid() -> Option<i32> {
+    let pid = std::fs::read_to_string(env::PID_FILE).ok()?;
+    pid.trim().parse().ok()
+}
+
+fn is_alive(pid: i32) -> bool {
+    nix::sys::signal::kill(Pid::from_raw(pid), None).is_ok()
+}
+
+fn path_check(name: &'static str, path: &str) -> util::Check {
+    let path = Path::new(path.trim_start_matches("unix://"));
+    match path.exists() {
+        true => util::Check::ok(name, path.display().to_string()),
+        false => util::Check::failed(name, format!("{} is missing", path.display())),
+    }
+}
+
+impl Running for XunleiStatus {
+    fn run(self) -> anyhow::Result<()> {
+        let pid = backend_pid();
+        let running = pid.is_some_and(is_alive);
+        let socket = path_check("backend socket", env::SOCK_FILE);
+        let pid_check = match pid {
+            Some(pid) if running => util::Check::ok("backend process", format!("PID {}", pid)),
+            Some(pid) => util::Check::failed("backend process", format!("PID {} is gone", pid)),
+            None => util::Check::failed("backend process", format!("no PID in {}", env::PID_FILE)),
+        };
+        let status = Status {
+            running,
+            pid,
+            socket_exists: socket.ok,
+            checks: vec![
+                pid_check,
+                socket,
+                path_check("launcher socket", env::LAUNCHER_SOCK),
+                path_check("backend program", env::LAUNCHER_EXE),
+                path_check("panel CGI program", env::SYNOPKG_CLI_WEB),
+            ],
+        };
+        util::print_report(&status, self.json)
+    }
+}
@@ -0,0 +1,228 @@
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use rustls::server::{ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::error::ErrorKind;
+use crate::http1::{self, Connection};
+use crate::listener::{self, BodyLimit, IDLE_TIMEOUT};
+use crate::{TlsCipherPolicy, TlsVersion};
+
+// A certificate chain and key, served for `host` or by default when it is None
+pub struct TlsIdentity {
+    pub host: Option<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// Picks the certificate matching the client SNI hostname, or the default one.
+// Without a default, unknown hostnames are rejected during the handshake
+struct SniResolver {
+    by_name: ResolvesServerCertUsingSni,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.by_name
+            .resolve(client_hello)
+            .or_else(|| self.default.clone())
+    }
+}
+
+fn load_certified_key(identity: &TlsIdentity) -> anyhow::Result<CertifiedKey> {
+    let context = |path: &Path| format!("[Tls] Failed to read {}", path.display());
+    let mut cert_reader =
+        BufReader::new(std::fs::File::open(&identity.cert).context(context(&identity.cert))?);
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .context(context(&identity.cert))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        anyhow::bail!("[Tls] No certificate found in {}", identity.cert.display())
+    }
+
+    let mut key_reader =
+        BufReader::new(std::fs::File::open(&identity.key).context(context(&identity.key))?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader).context(context(&identity.key))? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => anyhow::bail!("[Tls] No private key found in {}", identity.key.display()),
+        }
+    };
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| anyhow::anyhow!("[Tls] {}: {}", identity.key.display(), e))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+// Cipher suites of --tls-cipher-policy strict, TLS 1.3 first like the rustls defaults
+static STRICT_CIPHER_SUITES: &[rustls::SupportedCipherSuite] = &[
+    rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+    rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+    rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    rustls::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
+static STRICT_KX_GROUPS: &[&rustls::SupportedKxGroup] =
+    &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1];
+
+// Build the panel TLS configuration from the configured identities
+pub fn server_config(
+    identities: &[TlsIdentity],
+    min_version: TlsVersion,
+    cipher_policy: TlsCipherPolicy,
+) -> anyhow::Result<Arc<ServerConfig>> {
+    let mut resolver = SniResolver {
+        by_name: ResolvesServerCertUsingSni::new(),
+        default: None,
+    };
+    for identity in identities {
+        let certified_key = load_certified_key(identity).context(ErrorKind::Config)?;
+        match &identity.host {
+            Some(host) => {
+                resolver
+                    .by_name
+                    .add(host, certified_key)
+                    .map_err(|e| {
+                        anyhow::anyhow!(
+                            "[Tls] Certificate {} can't serve {}: {}",
+                            identity.cert.display(),
+                            host,
+                            e
+                        )
+                    })
+                    .context(ErrorKind::Config)?;
+                log::info!("[Tls] Serve {} for {}", identity.cert.display(), host);
+            }
+            None if resolver.default.is_some() => {
+                return Err(anyhow::anyhow!(
+                    "[Tls] Only one certificate may be served without a hostname"
+                )
+                .context(ErrorKind::Config));
+            }
+            None => {
+                resolver.default = Some(Arc::new(certified_key));
+                log::info!("[Tls] Serve {} by default", identity.cert.display());
+            }
+        }
+    }
+    if resolver.default.is_none() {
+        log::info!("[Tls] No default certificate, unknown hostnames are rejected");
+    }
+
+    let (versions, name): (&[&rustls::SupportedProtocolVersion], _) = match min_version {
+        TlsVersion::V1_2 => (&[&rustls::version::TLS13, &rustls::version::TLS12], "1.2"),
+        TlsVersion::V1_3 => (&[&rustls::version::TLS13], "1.3"),
+    };
+    let (suites, groups) = match cipher_policy {
+        TlsCipherPolicy::Standard => (rustls::DEFAULT_CIPHER_SUITES, &rustls::ALL_KX_GROUPS[..]),
+        TlsCipherPolicy::Strict => (STRICT_CIPHER_SUITES, STRICT_KX_GROUPS),
+    };
+    let config = ServerConfig::builder()
+        .with_cipher_suites(suites)
+        .with_kx_groups(groups)
+        .with_protocol_versions(versions)
+        .map_err(|e| anyhow::anyhow!("[Tls] {}", e))
+        .context(ErrorKind::Config)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    log::info!(
+        "[Tls] Minimum version TLS {}, {:?} cipher policy",
+        name,
+        cipher_policy
+    );
+    Ok(Arc::new(config))
+}
+
+// Serves rouille handlers over HTTP/1.1 on TLS connections, from a pool of workers
+pub struct Server<F> {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    body_limit: BodyLimit,
+    pool: threadpool::ThreadPool,
+    handler: Arc<AssertUnwindSafe<F>>,
+}
+
+impl<F> Server<F>
+where
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    pub fn from_listener(
+        listener: TcpListener,
+        config: Arc<ServerConfig>,
+        body_limit: BodyLimit,
+        handler: F,
+    ) -> Self {
+        Self {
+            listener,
+            config,
+            body_limit,
+            pool: threadpool::ThreadPool::new(listener::pool_size()),
+            handler: Arc::new(AssertUnwindSafe(handler)),
+        }
+    }
+
+    pub fn run(self) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::debug!("[Tls] Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let config = self.config.clone();
+            let body_limit = self.body_limit.clone();
+            let handler = self.handler.clone();
+            self.pool.execute(move || {
+                if let Err(e) = serve_connection(stream, config, &body_limit, &**handler) {
+                    log::debug!("[Tls] Connection closed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+impl Connection for StreamOwned<ServerConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+        self.sock.set_read_timeout(Some(timeout))
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()
+    }
+}
+
+fn serve_connection<F>(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    body_limit: &BodyLimit,
+    handler: &F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&rouille::Request) -> rouille::Response,
+{
+    let remote_addr = stream.peer_addr()?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let connection = ServerConnection::new(config)?;
+    http1::serve_requests(
+        StreamOwned::new(connection, stream),
+        remote_addr,
+        true,
+        body_limit,
+        handler,
+    )
+}
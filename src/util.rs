@@ -29,27 +29,29 @@ pub fn chown(target_path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
 }
 
 pub fn recursive_chown(path: &Path, uid: u32, gid: u32) {
-    chown(&path, uid, gid).expect(&format!(
-        "Failed to chown: {}, PUID:{}, GUID:{}",
-        path.display(),
-        uid,
-        gid
-    ));
+    chown(path, uid, gid).unwrap_or_else(|_| {
+        panic!(
+            "Failed to chown: {}, PUID:{}, GUID:{}",
+            path.display(),
+            uid,
+            gid
+        )
+    });
     if let Ok(entries) = fs::read_dir(path) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let dir_path = entry.path();
+        for entry in entries.flatten() {
+            let dir_path = entry.path();
 
-                chown(&dir_path, uid, gid).expect(&format!(
+            chown(&dir_path, uid, gid).unwrap_or_else(|_| {
+                panic!(
                     "Failed to chown: {}, PUID:{}, GUID:{}",
                     dir_path.display(),
                     uid,
                     gid
-                ));
+                )
+            });
 
-                if entry.file_type().unwrap().is_dir() {
-                    recursive_chown(&dir_path, uid, gid);
-                }
+            if entry.file_type().unwrap().is_dir() {
+                recursive_chown(&dir_path, uid, gid);
             }
         }
     }
@@ -71,6 +73,45 @@ pub fn write_file(target_path: &PathBuf, content: Cow<[u8]>, mode: u32) -> anyho
     Ok(())
 }
 
+// Create and remove a file in the directory, read-only mounts fail here instead of at the first real write
+pub fn probe_writable(dir: &Path) -> anyhow::Result<()> {
+    let probe = dir.join(format!(".xunlei-write-probe-{}", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .context(format!("{} is not writable", dir.display()))
+}
+
+// Read a line from stdin without echoing it on a terminal, piped input is read as is
+pub fn read_password(prompt: &str) -> anyhow::Result<String> {
+    use nix::sys::termios::{self, LocalFlags, SetArg};
+    use std::os::fd::AsRawFd;
+
+    let stdin = std::io::stdin();
+    let fd = stdin.as_raw_fd();
+    let saved = match nix::unistd::isatty(fd).unwrap_or(false) {
+        true => {
+            eprint!("{}", prompt);
+            let saved = termios::tcgetattr(fd)?;
+            let mut silent = saved.clone();
+            silent.local_flags.remove(LocalFlags::ECHO);
+            termios::tcsetattr(fd, SetArg::TCSANOW, &silent)?;
+            Some(saved)
+        }
+        false => None,
+    };
+    let mut line = String::new();
+    let read = stdin.read_line(&mut line);
+    if let Some(saved) = saved {
+        termios::tcsetattr(fd, SetArg::TCSANOW, &saved)?;
+        eprintln!();
+    }
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
 pub fn create_dir_all(target_path: &Path, mode: u32) -> anyhow::Result<()> {
     std::fs::create_dir_all(target_path).context(format!(
         "Failed to create folder: {}",
@@ -83,3 +124,166 @@ pub fn create_dir_all(target_path: &Path, mode: u32) -> anyhow::Result<()> {
     ))?;
     Ok(())
 }
+
+#[derive(serde::Serialize)]
+pub struct DiskUsage {
+    pub total: u64,
+    pub used: u64,
+    pub free: u64,
+}
+
+// Usage of the filesystem backing the path, free is the space available to unprivileged users
+pub fn disk_usage(path: &Path) -> anyhow::Result<DiskUsage> {
+    let stat = nix::sys::statvfs::statvfs(path)
+        .context(format!("Failed to statvfs: {}", path.display()))?;
+    let fragment_size = stat.fragment_size() as u64;
+    Ok(DiskUsage {
+        total: stat.blocks() as u64 * fragment_size,
+        used: (stat.blocks() - stat.blocks_free()) as u64 * fragment_size,
+        free: stat.blocks_available() as u64 * fragment_size,
+    })
+}
+
+// Whether the path is a mount point of the current mount namespace
+pub fn is_mounted(path: &Path) -> anyhow::Result<bool> {
+    let path =
+        fs::canonicalize(path).context(format!("Failed to canonicalize: {}", path.display()))?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .context("Failed to read /proc/self/mountinfo")?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mount_point| Path::new(&unescape_mount_point(mount_point)) == path))
+}
+
+// Mount points escape space, tab, newline and backslash as octal sequences
+fn unescape_mount_point(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let code: String = chars.by_ref().take(3).collect();
+            match u8::from_str_radix(&code, 8) {
+                Ok(b) => out.push(b as char),
+                Err(_) => {
+                    out.push(c);
+                    out.push_str(&code);
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Linux capabilities the launcher and installer depend on, numbered as in linux/capability.h
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    Chown = 0,
+    Setgid = 6,
+    Setuid = 7,
+    SysChroot = 18,
+    SysAdmin = 21,
+}
+
+impl Capability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Chown => "CAP_CHOWN",
+            Capability::Setgid => "CAP_SETGID",
+            Capability::Setuid => "CAP_SETUID",
+            Capability::SysChroot => "CAP_SYS_CHROOT",
+            Capability::SysAdmin => "CAP_SYS_ADMIN",
+        }
+    }
+}
+
+// Effective capability set of the process, None when /proc/self/status can't tell
+fn effective_capabilities() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(value.trim(), 16).ok()
+}
+
+pub fn has_capability(capability: Capability) -> bool {
+    match effective_capabilities() {
+        Some(effective) => effective & (1 << capability as u64) != 0,
+        None => nix::unistd::geteuid().is_root(),
+    }
+}
+
+// Fail with every missing capability and why it is needed, each requirement is a
+// capability, the operation needing it and how to do without it
+pub fn check_capabilities(required: &[(Capability, String, &str)]) -> anyhow::Result<()> {
+    let effective = match effective_capabilities() {
+        Some(effective) => effective,
+        // Without procfs only root is known to have them
+        None if nix::unistd::geteuid().is_root() => return Ok(()),
+        None => 0,
+    };
+    let missing = required
+        .iter()
+        .filter(|(capability, _, _)| effective & (1 << *capability as u64) == 0)
+        .map(|(capability, operation, hint)| {
+            format!("{} to {} ({})", capability.name(), operation, hint)
+        })
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "Insufficient privileges, run as root or grant the missing capabilities: {}",
+        missing.join("; ")
+    )
+}
+
+// One diagnostic result of a command report
+#[derive(serde::Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    pub fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            ok: true,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn failed(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            ok: false,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mark = match self.ok {
+            true => "ok",
+            false => "FAILED",
+        };
+        write!(f, "[{}] {}: {}", mark, self.name, self.detail)
+    }
+}
+
+// Print a command report as one JSON document for scripts, or as text by default
+pub fn print_report<T>(report: &T, json: bool) -> anyhow::Result<()>
+where
+    T: serde::Serialize + std::fmt::Display,
+{
+    match json {
+        true => println!("{}", serde_json::to_string(report)?),
+        false => print!("{}", report),
+    }
+    Ok(())
+}
@@ -2,12 +2,16 @@ use core::str;
 use std::borrow::Cow;
 
 #[cfg(not(feature = "embed"))]
-use std::{io::Write, ops::Not, path::PathBuf};
+use std::io::Write;
+use std::{
+    ops::Not,
+    path::{Path, PathBuf},
+};
 
 pub trait XunleiAsset {
     fn version(&self) -> anyhow::Result<String>;
 
-    fn get(&self, filename: &str) -> anyhow::Result<Cow<[u8]>>;
+    fn get(&self, filename: &str) -> anyhow::Result<Cow<'_, [u8]>>;
 
     fn iter(&self) -> anyhow::Result<Vec<String>>;
 }
@@ -32,7 +36,7 @@ impl XunleiAsset for XunleiEmbedAsset {
         Ok(String::from(version))
     }
 
-    fn get(&self, filename: &str) -> anyhow::Result<Cow<[u8]>> {
+    fn get(&self, filename: &str) -> anyhow::Result<Cow<'_, [u8]>> {
         let bin = Asset::get(filename).context("Failed to get bin asset")?;
         Ok(bin.data)
     }
@@ -44,29 +48,53 @@ impl XunleiAsset for XunleiEmbedAsset {
     }
 }
 
-#[cfg(not(feature = "embed"))]
 struct XunleiLocalAsset {
     tmp_path: PathBuf,
-    filename: String,
 }
 
-#[cfg(not(feature = "embed"))]
 impl XunleiLocalAsset {
+    #[cfg(not(feature = "embed"))]
     fn new() -> anyhow::Result<Self> {
         let xunlei = XunleiLocalAsset {
             tmp_path: PathBuf::from("/tmp/xunlei_bin"),
-            filename: format!("nasxunlei-DSM7-{}.spk", crate::env::SUPPORT_ARCH),
         };
-        let status = xunlei.exestrct_package()?;
+        let package =
+            xunlei.download_package(&format!("nasxunlei-DSM7-{}.spk", crate::env::SUPPORT_ARCH))?;
+        let status = xunlei.exestrct_package(&package)?;
         if status.success().not() {
             log::error!("[XunleiLocalAsset] There was an error extracting the download package")
         }
+        std::fs::remove_file(package)?;
+        Ok(xunlei)
+    }
+
+    fn from_file(package: &Path) -> anyhow::Result<Self> {
+        if package.is_file().not() {
+            anyhow::bail!(
+                "[XunleiLocalAsset] The package file does not exist: {}",
+                package.display()
+            )
+        }
+        let xunlei = XunleiLocalAsset {
+            tmp_path: PathBuf::from("/tmp/xunlei_upgrade"),
+        };
+        if xunlei.tmp_path.exists() {
+            std::fs::remove_dir_all(&xunlei.tmp_path)?;
+        }
+        crate::util::create_dir_all(&xunlei.tmp_path, 0o755)?;
+        let status = xunlei.exestrct_package(package)?;
+        if status.success().not() {
+            anyhow::bail!(
+                "[XunleiLocalAsset] There was an error extracting the package: {}",
+                package.display()
+            )
+        }
         Ok(xunlei)
     }
 
-    fn exestrct_package(&self) -> anyhow::Result<std::process::ExitStatus> {
-        let response =
-            ureq::get(&format!("http://down.sandai.net/nas/{}", self.filename)).call()?;
+    #[cfg(not(feature = "embed"))]
+    fn download_package(&self, filename: &str) -> anyhow::Result<PathBuf> {
+        let response = ureq::get(&format!("http://down.sandai.net/nas/{}", filename)).call()?;
 
         let total_size = response.header("Content-Length").unwrap().parse::<u64>()?;
 
@@ -81,7 +109,8 @@ impl XunleiLocalAsset {
         let mut downloaded = 0;
         let mut buf = [0; 1024];
         let mut reader = response.into_reader();
-        let mut output_file = std::fs::File::create(self.tmp_path.join(self.filename.as_str()))?;
+        let package = self.tmp_path.join(filename);
+        let mut output_file = std::fs::File::create(&package)?;
         loop {
             let n = reader.read(buf.as_mut())?;
             if n == 0 {
@@ -97,18 +126,21 @@ impl XunleiLocalAsset {
 
         output_file.flush()?;
         drop(output_file);
+        Ok(package)
+    }
 
+    fn exestrct_package(&self, package: &Path) -> anyhow::Result<std::process::ExitStatus> {
         let dir = self.tmp_path.display();
-        let filename = self.filename.as_str();
+        let package = package.display();
         Ok(std::process::Command::new("sh")
                 .arg("-c")
-                .arg(format!("tar --wildcards -Oxf $(find {dir} -type f -name {filename} | head -n1) package.tgz | tar --wildcards -xJC {dir} 'bin/bin/*' 'ui/index.cgi' &&
+                .arg(format!("tar --wildcards -Oxf {package} package.tgz | tar --wildcards -xJC {dir} 'bin/bin/*' 'ui/index.cgi' &&
                     mv {dir}/bin/bin/* {dir}/ &&
                     mv {dir}/ui/index.cgi {dir}/xunlei-pan-cli-web &&
                     rm -rf {dir}/bin/bin &&
                     rm -rf {dir}/bin &&
                     rm -rf {dir}/ui &&
-                    rm -f {dir}/version_code {dir}/{filename}
+                    rm -f {dir}/version_code
                 "))
                 .spawn()?
                 .wait()?
@@ -116,7 +148,6 @@ impl XunleiLocalAsset {
     }
 }
 
-#[cfg(not(feature = "embed"))]
 impl XunleiAsset for XunleiLocalAsset {
     fn version(&self) -> anyhow::Result<String> {
         Ok(std::fs::read_to_string(
@@ -124,7 +155,7 @@ impl XunleiAsset for XunleiLocalAsset {
         )?)
     }
 
-    fn get(&self, filename: &str) -> anyhow::Result<Cow<[u8]>> {
+    fn get(&self, filename: &str) -> anyhow::Result<Cow<'_, [u8]>> {
         let vec = std::fs::read(PathBuf::from(&self.tmp_path).join(filename))?;
         Ok(std::borrow::Cow::from(vec))
     }
@@ -132,12 +163,10 @@ impl XunleiAsset for XunleiLocalAsset {
     fn iter(&self) -> anyhow::Result<Vec<String>> {
         let entries = std::fs::read_dir(&self.tmp_path)?;
         let mut file_names = Vec::new();
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if let Some(file_name) = path.file_name() {
-                    file_names.push(file_name.to_string_lossy().to_string());
-                }
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if let Some(file_name) = path.file_name() {
+                file_names.push(file_name.to_string_lossy().to_string());
             }
         }
         Ok(file_names)
@@ -151,3 +180,40 @@ pub fn asset() -> anyhow::Result<impl XunleiAsset> {
     let asset = XunleiEmbedAsset {};
     Ok(asset)
 }
+
+// Extract the assets from a local xunlei spk package
+pub fn asset_from_file(package: &Path) -> anyhow::Result<impl XunleiAsset> {
+    XunleiLocalAsset::from_file(package)
+}
+
+// Extract the embedded assets again over an existing installation, replacing each file
+// atomically and verifying it against the embedded copy. Returns the names of the extracted files
+#[cfg(feature = "embed")]
+pub fn reextract(target_dir: &Path, uid: u32, gid: u32) -> anyhow::Result<Vec<String>> {
+    use sha3::{Digest, Sha3_256};
+    let xunlei = XunleiEmbedAsset {};
+    let mut extracted = Vec::new();
+    for filename in xunlei.iter()? {
+        let data = xunlei.get(&filename)?;
+        let target_filepath = target_dir.join(&filename);
+        let staged_filepath = target_dir.join(format!(".{}.reextract", filename));
+        crate::util::write_file(&staged_filepath, data.clone(), 0o755)?;
+        crate::util::chown(&staged_filepath, uid, gid)?;
+        std::fs::rename(&staged_filepath, &target_filepath)?;
+        if Sha3_256::digest(std::fs::read(&target_filepath)?) != Sha3_256::digest(&data) {
+            anyhow::bail!(
+                "[XunleiAsset] Checksum mismatch after re-extracting: {}",
+                target_filepath.display()
+            )
+        }
+        log::info!("[XunleiAsset] Re-extract to: {}", target_filepath.display());
+        extracted.push(filename);
+    }
+    Ok(extracted)
+}
+
+// Without embedded assets they would be downloaded again, over plain HTTP and unverified
+#[cfg(not(feature = "embed"))]
+pub fn reextract(_target_dir: &Path, _uid: u32, _gid: u32) -> anyhow::Result<Vec<String>> {
+    anyhow::bail!("[XunleiAsset] Re-extracting needs a build with the embed feature")
+}